@@ -0,0 +1,82 @@
+// Measures how fast the output of `chef-client` is copied to the log and the
+// terminal. Run with `cargo bench`.
+extern crate chefctl;
+
+use chefctl::process::{pump, Pending};
+use std::{
+    fs::File,
+    io::{sink, Read},
+    time::Instant,
+};
+
+// Roughly what a verbose run looks like, including the odd byte that is not
+// UTF-8 from a package manager.
+const SAMPLE: &[u8] = b"[2019-01-15T10:30:00-08:00] DEBUG: Resource for package[vim] is Chef::Resource::YumPackage\n\
+    [2019-01-15T10:30:00-08:00] INFO: Processing yum_package[vim] action upgrade (base::default line 12)\n\
+    Installing: vim-enhanced-8.0.1763-13.el7.x86_64 \xe2\x96\x88\xe2\x96\x88\xe2\x96\x88 \xff\xfe\n\
+    \n";
+
+const TOTAL_BYTES: u64 = 512 * 1024 * 1024;
+
+// Produces `SAMPLE` over and over, handing out at most `max_read` bytes at a
+// time like a pipe the child writes small pieces into.
+struct Output {
+    left: u64,
+    offset: usize,
+    max_read: usize,
+}
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut n = 0;
+        let limit = buf.len().min(self.max_read) as u64;
+
+        while (n as u64) < limit.min(self.left) {
+            let take = (SAMPLE.len() - self.offset)
+                .min(buf.len() - n)
+                .min(self.max_read - n)
+                .min(self.left as usize);
+            buf[n..n + take].copy_from_slice(&SAMPLE[self.offset..self.offset + take]);
+            n += take;
+            self.left -= take as u64;
+            self.offset = (self.offset + take) % SAMPLE.len();
+        }
+
+        Ok(n)
+    }
+}
+
+// chef-client writing faster than it is read.
+impl Pending for Output {
+    fn pending(&self) -> bool {
+        self.left > 0
+    }
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("chefctl-bench-{}.out", std::process::id()));
+
+    for &(name, max_read) in &[("large reads", usize::MAX), ("one line per read", 100)] {
+        let output = Output {
+            left: TOTAL_BYTES,
+            offset: 0,
+            max_read,
+        };
+        let log = File::create(&path).unwrap();
+
+        let started = Instant::now();
+        let copied = pump(output, log, sink()).unwrap();
+        let elapsed = started.elapsed();
+
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        println!(
+            "{:<18} {} MiB in {:.2}s, {:.0} MiB/s",
+            name,
+            copied / 1024 / 1024,
+            secs,
+            copied as f64 / 1024.0 / 1024.0 / secs
+        );
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -1,39 +1,875 @@
-use actix_web::{server, HttpRequest};
+use crate::{
+    auth::Auth,
+    config::{ApiConfig, ConfigError, Listen, TlsConfig},
+    daemon::{RunRequest, Submitted, RUN_QUEUE},
+    events::{self, EVENTS},
+    health::HEALTH_STATE,
+    history::{parse_since, Filter, History},
+    metrics::Snapshot,
+    process::{Outcome, OUTPUT},
+    state::{RunSummary, APP_STATE},
+    VERSION,
+};
+#[cfg(not(target_os = "windows"))]
+use actix_net::service::{NewService, Service};
+#[cfg(not(target_os = "windows"))]
+use actix_web::server::{HttpHandler, HttpService, IntoHttpHandler, IoStream, ServiceConfig};
+use actix_web::{
+    dev::{HttpResponseBuilder, QueryConfig},
+    error::ErrorInternalServerError,
+    http::Method,
+    server, FromRequest, FutureResponse, HttpRequest, HttpResponse, Query,
+};
+use bytes::Bytes;
+use futures::{
+    future, stream,
+    sync::mpsc::{channel, Receiver},
+    Future, Sink, Stream,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
 
-pub fn start_api_server(addr: &str) -> std::io::Result<()> {
-    server::new(|| {
-        actix_web::App::new()
-            .resource("/", |r| r.f(index))
-            .resource("/health", |r| r.f(health))
-    })
-    .bind(addr)?
-    .run();
+lazy_static! {
+    // The Unix domain socket the API is served on by this process.
+    static ref SOCKET: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+// Removes the socket the API is served on, if any, before the process exits.
+pub fn remove_socket() {
+    if let Some(path) = SOCKET.lock().unwrap().take() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// How long `POST /cancel` waits for the run to stop, `chef-client` is killed
+// after `KILL_GRACE_PERIOD`.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Size of the chunks a log file is replayed in.
+const REPLAY_CHUNK_SIZE: u64 = 64 * 1024;
+
+// Number of chunks read ahead of a client replaying a log.
+const REPLAY_BACKLOG: usize = 4;
+
+// Every version of the API is served below its own prefix.
+pub const API_PREFIX: &str = "/v1";
+
+pub struct Endpoint {
+    pub method: Method,
+    // Relative to `API_PREFIX`.
+    pub path: &'static str,
+    // Where the endpoint was served before the API was versioned.
+    pub legacy_path: Option<&'static str>,
+    handler: Handler,
+}
+
+pub enum Handler {
+    Sync(fn(&HttpRequest) -> HttpResponse),
+    // Responds once the future resolves, without blocking the arbiter.
+    Async(fn(&HttpRequest) -> FutureResponse<HttpResponse>),
+}
+
+pub const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: Method::GET,
+        path: "/status",
+        legacy_path: Some("/"),
+        handler: Handler::Sync(status),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/health",
+        legacy_path: Some("/health"),
+        handler: Handler::Sync(health),
+    },
+    Endpoint {
+        method: Method::POST,
+        path: "/run",
+        legacy_path: Some("/run"),
+        handler: Handler::Sync(run),
+    },
+    Endpoint {
+        method: Method::POST,
+        path: "/cancel",
+        legacy_path: Some("/cancel"),
+        handler: Handler::Async(cancel),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/logs/current",
+        legacy_path: Some("/logs/current"),
+        handler: Handler::Sync(current_log),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/events",
+        legacy_path: Some("/events"),
+        handler: Handler::Sync(events),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/metrics",
+        legacy_path: Some("/metrics"),
+        handler: Handler::Sync(metrics),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/history",
+        legacy_path: None,
+        handler: Handler::Sync(history),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/history/{run_id}",
+        legacy_path: None,
+        handler: Handler::Sync(history_run),
+    },
+    Endpoint {
+        method: Method::GET,
+        path: "/openapi.json",
+        legacy_path: None,
+        handler: Handler::Sync(openapi),
+    },
+];
+
+// Describes `ENDPOINTS` and the types they respond with. The tests below fail
+// when either changes without this document.
+const OPENAPI: &str = include_str!("openapi.json");
+
+fn app(auth: Auth) -> actix_web::App {
+    let mut app = actix_web::App::new()
+        .middleware(auth)
+        .default_resource(|r| r.f(not_found));
+
+    for e in ENDPOINTS {
+        let paths = std::iter::once(format!("{}{}", API_PREFIX, e.path))
+            .chain(e.legacy_path.map(String::from));
+        for path in paths {
+            app = app.resource(&path, move |r| {
+                match e.handler {
+                    Handler::Sync(f) => r.method(e.method.clone()).f(f),
+                    Handler::Async(f) => r.method(e.method.clone()).f(f),
+                }
+                r.f(method_not_allowed);
+            });
+        }
+    }
+
+    app
+}
+
+fn invalid_config(e: ConfigError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+}
+
+// Serves the API until the process exits. Returns right away when the API is
+// disabled.
+pub fn start_api_server(config: &ApiConfig) -> std::io::Result<()> {
+    let listen = config.listen().map_err(invalid_config)?;
+    if listen == Listen::Disabled {
+        return Ok(());
+    }
+    let auth = Auth::from_config(config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    match listen {
+        Listen::Disabled => Ok(()),
+        Listen::Tcp(addr) => match config.tls {
+            Some(ref tls) => serve_tls(&addr, tls, auth),
+            None if is_loopback(&addr)? => {
+                server::new(move || app(auth.clone())).bind(addr)?.run();
+
+                Ok(())
+            }
+            // Anything that leaves the host has to be encrypted.
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a loopback address, configure api.tls to serve on it",
+                    addr
+                ),
+            )),
+        },
+        Listen::Unix(path) => serve_unix(&path, config, auth),
+    }
+}
+
+// Serves a connection on the current thread once `conn` has resolved to it,
+// e.g. after the TLS handshake. Connections are handed to the HTTP service
+// directly rather than through `HttpServer`, which only accepts plain TCP and
+// would hide the peer credentials of Unix domain sockets from requests.
+#[cfg(not(target_os = "windows"))]
+fn spawn_connection<H, F, T>(settings: &ServiceConfig<H>, conn: F)
+where
+    H: HttpHandler + 'static,
+    F: Future<Item = T, Error = std::io::Error> + 'static,
+    T: IoStream,
+{
+    let mut service = HttpService::new(settings.clone())
+        .new_service()
+        .wait()
+        .expect("creating the http service cannot fail");
+
+    actix::Arbiter::spawn(
+        conn.map_err(|e| eprintln!("api connection failed: {}", e))
+            .and_then(move |io| service.call(io).map_err(|_| ())),
+    );
+}
+
+#[cfg(not(target_os = "windows"))]
+fn serve_unix(path: &Path, config: &ApiConfig, auth: Auth) -> std::io::Result<()> {
+    use crate::{
+        auth::PeerStream,
+        platform::{chown, lookup_group, lookup_user},
+    };
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    let mode = config.socket_mode().map_err(invalid_config)?;
+    let owner = match config.socket_owner {
+        Some(ref o) => Some(lookup_user(o)?),
+        None => None,
+    };
+    let group = match config.socket_group {
+        Some(ref g) => Some(lookup_group(g)?),
+        None => None,
+    };
+
+    // Another chefctl, most likely the daemon, is still serving on it.
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("another chefctl is serving on {}", path.display()),
+        ));
+    }
+    // Only ever replace a socket left behind by a previous chefctl.
+    if let Ok(m) = std::fs::symlink_metadata(path) {
+        if !m.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+    }
+
+    let sys = actix::System::new("chefctl-api");
+
+    // The socket is bound in a directory nobody else can enter and only moved
+    // into place once its permissions are, so nobody can connect before.
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("api.sock");
+    let listener = tokio_uds::UnixListener::bind(&bound).and_then(|l| {
+        if owner.is_some() || group.is_some() {
+            chown(&bound, owner, group)?;
+        }
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&bound, path)?;
+
+        Ok(l)
+    });
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private);
+    let listener = listener?;
+    *SOCKET.lock().unwrap() = Some(path.to_path_buf());
+
+    let settings = ServiceConfig::build(app(auth).into_handler()).finish();
+    actix::Arbiter::spawn(
+        listener
+            .incoming()
+            .for_each(move |stream| {
+                spawn_connection(&settings, future::ok(PeerStream::new(stream)));
+
+                Ok(())
+            })
+            .map_err(|e| eprintln!("api server stopped accepting connections: {}", e)),
+    );
+    sys.run();
 
     Ok(())
 }
 
-pub fn index(_req: &HttpRequest) -> String {
-    let state_json = match serde_json::to_string_pretty(&*crate::state::APP_STATE) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("error serializing state: {}", e);
+#[cfg(target_os = "windows")]
+fn serve_unix(_: &Path, _: &ApiConfig, _: Auth) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "unix domain sockets are not supported on windows",
+    ))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn serve_tls(addr: &str, config: &TlsConfig, auth: Auth) -> std::io::Result<()> {
+    use crate::tls::{acceptor, Handshake};
 
-            "{\"error\": \"serialization error!\"}".into()
+    let acceptor = acceptor(config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let addr = match addr.to_socket_addrs()?.next() {
+        Some(a) => a,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("\"{}\" does not resolve to an address", addr),
+            ))
         }
     };
 
-    format!("{}\n", state_json).to_string()
+    let sys = actix::System::new("chefctl-api");
+    let listener = tokio_tcp::TcpListener::bind(&addr)?;
+
+    let settings = ServiceConfig::build(app(auth).into_handler())
+        .secure()
+        .finish();
+    actix::Arbiter::spawn(
+        listener
+            .incoming()
+            .for_each(move |stream| {
+                spawn_connection(&settings, Handshake::new(&acceptor, stream));
+
+                Ok(())
+            })
+            .map_err(|e| eprintln!("api server stopped accepting connections: {}", e)),
+    );
+    sys.run();
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn serve_tls(_: &str, _: &TlsConfig, _: Auth) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "tls is not supported on windows",
+    ))
+}
+
+// Whether every address `addr` resolves to is a loopback address.
+fn is_loopback(addr: &str) -> std::io::Result<bool> {
+    let mut addrs = addr.to_socket_addrs()?.peekable();
+    if addrs.peek().is_none() {
+        return Ok(false);
+    }
+
+    Ok(addrs.all(|a| a.ip().is_loopback()))
 }
 
-pub fn health(_req: &HttpRequest) -> String {
-    let state_json = match serde_json::to_string_pretty(&*crate::health::HEALTH_STATE) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("error serializing state: {}", e);
+// The body of every response that reports an error.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub error: String,
+    // The run the error is about, e.g. the one blocking a new run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
 
-            "{\"error\": \"serialization error!\"}".into()
+impl ApiError {
+    pub fn new<S: Into<String>>(error: S) -> Self {
+        Self {
+            error: error.into(),
+            run_id: None,
         }
+    }
+}
+
+// Serializes `body` into a response with the status of `resp`. A body that
+// cannot be serialized turns into a 500 rather than a broken 200.
+fn json<T: Serialize>(mut resp: HttpResponseBuilder, body: &T) -> HttpResponse {
+    match serde_json::to_string(body) {
+        Ok(b) => resp.content_type("application/json").body(b),
+        Err(e) => HttpResponse::InternalServerError().json(ApiError::new(format!(
+            "could not serialize response: {}",
+            e
+        ))),
+    }
+}
+
+fn not_found(_req: &HttpRequest) -> HttpResponse {
+    json(HttpResponse::NotFound(), &ApiError::new("no such endpoint"))
+}
+
+fn method_not_allowed(req: &HttpRequest) -> HttpResponse {
+    json(
+        HttpResponse::MethodNotAllowed(),
+        &ApiError::new(format!("{} is not supported here", req.method())),
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub process_state: String,
+    pub run_id: Option<String>,
+    pub splay: u64,
+    pub splay_countdown: u64,
+    pub log_path: String,
+    pub next_run: Option<String>,
+    pub last_run: Option<RunSummary>,
+    pub consecutive_failures: u64,
+    pub runs_total: BTreeMap<String, u64>,
+}
+
+pub fn status(_req: &HttpRequest) -> HttpResponse {
+    json(
+        HttpResponse::Ok(),
+        &StatusResponse {
+            process_state: APP_STATE.process_state(),
+            run_id: APP_STATE.run_id(),
+            splay: APP_STATE.splay(),
+            splay_countdown: APP_STATE.splay_countdown(),
+            log_path: APP_STATE.log_path(),
+            next_run: APP_STATE.next_run(),
+            last_run: APP_STATE.last_run(),
+            consecutive_failures: APP_STATE.consecutive_failures(),
+            runs_total: APP_STATE.runs_total(),
+        },
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckStatus {
+    pub passed: bool,
+    // What the check found, e.g. the version of chef-client.
+    pub result: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub healthy: bool,
+    pub checks: BTreeMap<String, CheckStatus>,
+}
+
+// Answers 503 unless every check has passed, so that load balancers can probe
+// it by status code alone. Nothing is healthy before the checks have run once.
+pub fn health(_req: &HttpRequest) -> HttpResponse {
+    let passed = HEALTH_STATE.passed();
+    let checks: BTreeMap<String, CheckStatus> = HEALTH_STATE
+        .checks()
+        .into_iter()
+        .map(|(name, result)| {
+            let status = CheckStatus {
+                passed: passed.get(&name).cloned().unwrap_or(false),
+                result,
+            };
+
+            (name, status)
+        })
+        .collect();
+    let healthy = !checks.is_empty() && checks.values().all(|c| c.passed);
+
+    let resp = if healthy {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+
+    json(resp, &HealthResponse { healthy, checks })
+}
+
+// Options accepted by `POST /run` as query parameters.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RunParams {
+    skip_splay: bool,
+    why_run: bool,
+    run_list: Option<String>,
+    // Queue the run behind the one in progress instead of answering 409.
+    queue: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunAccepted {
+    pub run_id: String,
+}
+
+pub fn run(req: &HttpRequest) -> HttpResponse {
+    let params = match Query::<RunParams>::from_request(req, &QueryConfig::default()) {
+        Ok(p) => p.into_inner(),
+        Err(e) => return json(HttpResponse::BadRequest(), &ApiError::new(e.to_string())),
+    };
+
+    if let Some(ref run_list) = params.run_list {
+        if run_list.is_empty() || run_list.contains(char::is_whitespace) {
+            return json(
+                HttpResponse::BadRequest(),
+                &ApiError::new("run_list must be a non-empty, comma separated list"),
+            );
+        }
+    }
+
+    let req = RunRequest {
+        skip_splay: params.skip_splay,
+        why_run: params.why_run,
+        run_list: params.run_list,
+        ..RunRequest::new()
+    };
+
+    match RUN_QUEUE.submit(req, params.queue) {
+        Submitted::Accepted(run_id) => json(HttpResponse::Accepted(), &RunAccepted { run_id }),
+        Submitted::Busy(run_id) => json(
+            HttpResponse::Conflict(),
+            &ApiError {
+                run_id: Some(run_id),
+                ..ApiError::new("a run is already in progress")
+            },
+        ),
+        Submitted::Conflicting(run_id) => json(
+            HttpResponse::Conflict(),
+            &ApiError {
+                run_id: Some(run_id),
+                ..ApiError::new("a run with different options is already queued")
+            },
+        ),
+        Submitted::Unavailable => json(
+            HttpResponse::ServiceUnavailable(),
+            &ApiError::new("runs can only be requested while chefctl runs as a daemon"),
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CancelResult {
+    pub process_state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+// Stops the run in progress and responds with the state it ended in. Should
+// `chef-client` take longer than `CANCEL_TIMEOUT` to stop, 202 is returned
+// instead and the `run_finished` event on `/events` tells when it has.
+pub fn cancel(_req: &HttpRequest) -> FutureResponse<HttpResponse> {
+    let run_id = APP_STATE.run_id();
+    // Subscribed first so that the end of the run cannot be missed.
+    let ((), rx) = EVENTS.subscribe(|| ());
+
+    if !crate::process::cancel() {
+        return Box::new(future::ok(json(
+            HttpResponse::Conflict(),
+            &ApiError::new("no run in progress"),
+        )));
+    }
+
+    let this_run = run_id.clone();
+    let finished = rx
+        .filter_map(move |msg| match events::parse(&msg) {
+            Some((ref kind, ref data))
+                if kind == "run_finished" && data["run_id"].as_str() == this_run.as_deref() =>
+            {
+                serde_json::from_value::<Outcome>(data["outcome"].clone()).ok()
+            }
+            _ => None,
+        })
+        .into_future()
+        .map(|(outcome, _)| outcome)
+        .map_err(|_| ());
+    let timeout = Delay::new(Instant::now() + CANCEL_TIMEOUT)
+        .map(|()| None)
+        .map_err(|_| ());
+
+    Box::new(finished.select(timeout).then(move |r| {
+        let res = match r.ok().and_then(|(outcome, _)| outcome) {
+            // The run ended on its own before it could be stopped.
+            Some(Outcome::Success) | Some(Outcome::Failure) => json(
+                HttpResponse::Ok(),
+                &CancelResult {
+                    process_state: "post-run".into(),
+                    run_id,
+                },
+            ),
+            Some(Outcome::Cancelled) => json(
+                HttpResponse::Ok(),
+                &CancelResult {
+                    process_state: "cancelled".into(),
+                    run_id,
+                },
+            ),
+            None => json(
+                HttpResponse::Accepted(),
+                &CancelResult {
+                    process_state: "cancelling".into(),
+                    run_id,
+                },
+            ),
+        };
+
+        Ok(res)
+    }))
+}
+
+// Streams the first `len` bytes of the file at `path`. The file is read on a
+// thread of its own so that a slow disk cannot hold up the arbiter, which
+// serves every other request as well.
+fn replay_log(path: String, len: u64) -> Receiver<Bytes> {
+    let (mut tx, rx) = channel(REPLAY_BACKLOG);
+
+    let _ = std::thread::Builder::new()
+        .name("replay-log".into())
+        .spawn(move || {
+            let mut file = match File::open(path) {
+                Ok(f) => f.take(len),
+                Err(_) => return,
+            };
+            loop {
+                let mut buf = vec![0; REPLAY_CHUNK_SIZE as usize];
+                match file.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.truncate(n),
+                }
+                // Blocks while the client is behind, ends once it is gone.
+                tx = match tx.send(Bytes::from(buf)).wait() {
+                    Ok(tx) => tx,
+                    Err(_) => return,
+                };
+            }
+        });
+
+    rx
+}
+
+// Follows the output of the run in progress using chunked transfer encoding.
+// With `?from=start` whatever has already been written to the log file is sent
+// first. The response ends together with the run.
+pub fn current_log(req: &HttpRequest) -> HttpResponse {
+    let from_start = req.query().get("from").map(String::as_str) == Some("start");
+    let log_path = APP_STATE.log_path();
+    let (written, live) = OUTPUT.subscribe(|| match std::fs::metadata(&log_path) {
+        Ok(m) if from_start => m.len(),
+        _ => 0,
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .streaming(
+            replay_log(log_path, written)
+                .chain(live)
+                .map_err(|_| ErrorInternalServerError("log stream failed")),
+        )
+}
+
+// Server-Sent Events for every change of the process state, the splay
+// countdown, the health checks and the outcome of every run. The current
+// process state is sent first so clients do not need to poll `/` as well.
+pub fn events(_req: &HttpRequest) -> HttpResponse {
+    let ((), rx) = EVENTS.subscribe(|| ());
+    let current = events::format(
+        "process_state",
+        json!({ "process_state": APP_STATE.process_state() }),
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(
+            stream::once(Ok(current))
+                .chain(rx)
+                .map_err(|_| ErrorInternalServerError("event stream failed")),
+        )
+}
+
+pub fn metrics(_req: &HttpRequest) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(Snapshot::collect().to_prometheus())
+}
+
+// Filters accepted by `GET /history` as query parameters.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryParams {
+    pub failed: bool,
+    pub since: Option<String>,
+    pub last: Option<usize>,
+}
+
+// Past runs matching the filters, most recent first.
+pub fn history(req: &HttpRequest) -> HttpResponse {
+    let params = match Query::<HistoryParams>::from_request(req, &QueryConfig::default()) {
+        Ok(p) => p.into_inner(),
+        Err(e) => return json(HttpResponse::BadRequest(), &ApiError::new(e.to_string())),
+    };
+    let since = match params.since.as_ref().map(|s| parse_since(s)) {
+        Some(Err(e)) => return json(HttpResponse::BadRequest(), &ApiError::new(e.to_string())),
+        Some(Ok(t)) => Some(t),
+        None => None,
     };
+    let filter = Filter {
+        failed: params.failed,
+        since,
+        last: params.last,
+    };
+
+    match History::from_config().records() {
+        Ok(records) => json(HttpResponse::Ok(), &filter.apply(records)),
+        Err(e) => json(
+            HttpResponse::InternalServerError(),
+            &ApiError::new(format!("could not read the history: {}", e)),
+        ),
+    }
+}
+
+pub fn history_run(req: &HttpRequest) -> HttpResponse {
+    let run_id = req.match_info().get("run_id").unwrap_or_default();
+
+    match History::from_config().find(run_id) {
+        Ok(Some(record)) => json(HttpResponse::Ok(), &record),
+        Ok(None) => json(
+            HttpResponse::NotFound(),
+            &ApiError {
+                run_id: Some(run_id.into()),
+                ..ApiError::new("no such run in the history")
+            },
+        ),
+        Err(e) => json(
+            HttpResponse::InternalServerError(),
+            &ApiError::new(format!("could not read the history: {}", e)),
+        ),
+    }
+}
+
+pub fn openapi(_req: &HttpRequest) -> HttpResponse {
+    match serde_json::from_str::<Value>(OPENAPI) {
+        Ok(mut doc) => {
+            doc["info"]["version"] = VERSION.into();
+
+            json(HttpResponse::Ok(), &doc)
+        }
+        Err(e) => json(
+            HttpResponse::InternalServerError(),
+            &ApiError::new(format!("could not parse the api description: {}", e)),
+        ),
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    fn description() -> serde_json::Value {
+        serde_json::from_str(super::OPENAPI).unwrap()
+    }
+
+    #[test]
+    fn every_endpoint_is_described() {
+        use super::{API_PREFIX, ENDPOINTS};
+
+        let doc = description();
+        let paths = doc["paths"].as_object().unwrap();
+
+        for e in ENDPOINTS {
+            let path = format!("{}{}", API_PREFIX, e.path);
+            let method = e.method.as_str().to_lowercase();
+
+            assert!(
+                paths.get(&path).and_then(|p| p.get(&method)).is_some(),
+                "{} {} is not described",
+                e.method,
+                path
+            );
+        }
+
+        let described: usize = paths.values().map(|p| p.as_object().unwrap().len()).sum();
+        assert_eq!(
+            described,
+            ENDPOINTS.len(),
+            "an endpoint is described but not served"
+        );
+    }
+
+    #[test]
+    fn schemas_match_response_types() {
+        use super::{
+            ApiError, CancelResult, CheckStatus, HealthResponse, RunAccepted, StatusResponse,
+        };
+        use crate::history::RunRecord;
+        use crate::{process::Outcome, state::RunSummary};
+        use serde_json::{to_value, Value};
+        use std::collections::{BTreeMap, BTreeSet};
+
+        let doc = description();
+        let fields =
+            |v: Value| -> BTreeSet<String> { v.as_object().unwrap().keys().cloned().collect() };
+        let described = |name: &str| -> BTreeSet<String> {
+            fields(doc["components"]["schemas"][name]["properties"].clone())
+        };
+
+        let run = RunSummary {
+            run_id: String::new(),
+            outcome: Outcome::Success,
+            exit_code: Some(0),
+            started_at: 0,
+            finished_at: 0,
+            duration: 0.0,
+        };
+        let status = StatusResponse {
+            process_state: "idle".into(),
+            run_id: None,
+            splay: 0,
+            splay_countdown: 0,
+            log_path: String::new(),
+            next_run: None,
+            last_run: None,
+            consecutive_failures: 0,
+            runs_total: BTreeMap::new(),
+        };
+        let check = CheckStatus {
+            passed: true,
+            result: "true".into(),
+        };
+        let health = HealthResponse {
+            healthy: true,
+            checks: BTreeMap::new(),
+        };
+        let error = ApiError {
+            run_id: Some(String::new()),
+            ..ApiError::new("")
+        };
+        let record = RunRecord {
+            trigger: crate::process::Trigger::Api,
+            summary: run.clone(),
+            splay: 0,
+            args: String::new(),
+            signal: None,
+            log_path: String::new(),
+            resources_updated: None,
+            resources_total: None,
+        };
+        let cancelled = CancelResult {
+            process_state: "cancelled".into(),
+            run_id: Some(String::new()),
+        };
 
-    format!("{}\n", state_json).to_string()
+        assert_eq!(fields(to_value(run).unwrap()), described("RunSummary"));
+        assert_eq!(
+            fields(to_value(status).unwrap()),
+            described("StatusResponse")
+        );
+        assert_eq!(fields(to_value(check).unwrap()), described("CheckStatus"));
+        assert_eq!(
+            fields(to_value(health).unwrap()),
+            described("HealthResponse")
+        );
+        assert_eq!(fields(to_value(error).unwrap()), described("ApiError"));
+        assert_eq!(fields(to_value(record).unwrap()), described("RunRecord"));
+        assert_eq!(
+            fields(
+                to_value(RunAccepted {
+                    run_id: String::new()
+                })
+                .unwrap()
+            ),
+            described("RunAccepted")
+        );
+        assert_eq!(
+            fields(to_value(cancelled).unwrap()),
+            described("CancelResult")
+        );
+    }
 }
@@ -0,0 +1,382 @@
+use crate::{api::ApiError, config::ApiConfig};
+use actix_web::{
+    http::{header, Method},
+    middleware::{Middleware, Response, Started},
+    HttpRequest, HttpResponse,
+};
+use chrono::prelude::Local;
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+#[derive(Debug)]
+pub struct AuthError(String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "auth error: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<std::io::Error> for AuthError {
+    fn from(e: std::io::Error) -> AuthError {
+        AuthError(e.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for AuthError {
+    fn from(e: serde_yaml::Error) -> AuthError {
+        AuthError(e.to_string())
+    }
+}
+
+// What a token may do. Admin tokens can do everything read tokens can.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Token {
+    // Identifies the caller in the audit log, e.g. `monitoring`.
+    pub name: String,
+    pub role: Role,
+    pub token: String,
+}
+
+// The contents of the tokens file, a list of tokens such as:
+//
+//      - name: monitoring
+//        role: read
+//        token: 8c1d...
+#[derive(Debug, Default)]
+pub struct Tokens(Vec<Token>);
+
+impl Tokens {
+    pub fn parse(content: &str) -> Result<Self, AuthError> {
+        let tokens: Vec<Token> = serde_yaml::from_str(content)?;
+
+        if let Some(t) = tokens.iter().find(|t| t.token.len() < 16) {
+            return Err(AuthError(format!(
+                "the token of \"{}\" is shorter than 16 characters",
+                t.name
+            )));
+        }
+
+        Ok(Tokens(tokens))
+    }
+
+    // The file holds secrets, so it is refused unless only root can read it.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, AuthError> {
+        let mut file = std::fs::File::open(&path)?;
+        check_permissions(&file.metadata()?, path.as_ref())?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        Self::parse(&content)
+    }
+
+    pub fn find(&self, token: &str) -> Option<&Token> {
+        self.0.iter().find(|t| constant_time_eq(&t.token, token))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_permissions(m: &std::fs::Metadata, path: &Path) -> Result<(), AuthError> {
+    use std::os::unix::fs::MetadataExt;
+
+    if m.uid() != 0 || m.mode() & 0o077 != 0 {
+        return Err(AuthError(format!(
+            "\"{}\" must be owned by root and not be accessible by anyone else",
+            path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn check_permissions(_: &std::fs::Metadata, _: &Path) -> Result<(), AuthError> {
+    Ok(())
+}
+
+// Compares in time independent of where the strings differ, so that tokens
+// cannot be guessed one character at a time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// Reading requires a read token, everything else an admin token.
+fn required_role(method: &Method) -> Role {
+    match *method {
+        Method::GET | Method::HEAD => Role::Read,
+        _ => Role::Admin,
+    }
+}
+
+fn peer_is_root<S>(req: &HttpRequest<S>) -> bool {
+    req.stream_extensions()
+        .and_then(|e| e.get::<PeerCred>().map(|c| c.uid == 0))
+        .unwrap_or(false)
+}
+
+// Credentials of the process on the other end of a Unix domain socket.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// The token a request was authenticated with.
+#[derive(Debug, Clone)]
+struct Caller(String);
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    status: u16,
+    caller: Option<String>,
+    peer_uid: Option<u32>,
+    peer_addr: Option<String>,
+}
+
+// Checks the bearer token of every request against the tokens file, if one
+// is configured, and records every request that is not a read in the audit
+// log. Without a tokens file anyone who can reach the API may read, but only
+// root connecting over the Unix domain socket may change anything.
+#[derive(Clone)]
+pub struct Auth {
+    tokens: Option<Arc<Tokens>>,
+    audit_log: String,
+}
+
+impl Auth {
+    pub fn from_config(config: &ApiConfig) -> Result<Self, AuthError> {
+        let tokens = match config.tokens_file {
+            Some(ref path) => Some(Arc::new(Tokens::from_path(path)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            tokens,
+            audit_log: config.audit_log.clone(),
+        })
+    }
+
+    // Rejected requests never reach `Middleware::response`, so attempts to
+    // change something are audited here.
+    fn reject<S>(&self, req: &HttpRequest<S>, resp: HttpResponse) -> Started {
+        if required_role(req.method()) == Role::Admin {
+            self.audit(req, &resp);
+        }
+
+        Started::Response(resp)
+    }
+
+    fn audit<S>(&self, req: &HttpRequest<S>, resp: &HttpResponse) {
+        let entry = AuditEntry {
+            timestamp: Local::now().to_rfc3339(),
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            status: resp.status().as_u16(),
+            caller: req.extensions().get::<Caller>().map(|c| c.0.clone()),
+            peer_uid: req
+                .stream_extensions()
+                .and_then(|e| e.get::<PeerCred>())
+                .map(|c| c.uid),
+            peer_addr: req.peer_addr().map(|a| a.to_string()),
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("could not serialize audit log entry: {}", e);
+                return;
+            }
+        };
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(not(target_os = "windows"))]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let written = options
+            .open(&self.audit_log)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        if let Err(e) = written {
+            eprintln!("could not write audit log \"{}\": {}", self.audit_log, e);
+        }
+    }
+}
+
+impl<S> Middleware<S> for Auth {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        let tokens = match self.tokens {
+            Some(ref t) => t,
+            None if required_role(req.method()) == Role::Read || peer_is_root(req) => {
+                return Ok(Started::Done)
+            }
+            None => {
+                let resp = HttpResponse::Forbidden().json(ApiError::new(
+                    "changing anything requires api.tokens_file or root on the unix socket",
+                ));
+                return Ok(self.reject(req, resp));
+            }
+        };
+
+        let presented = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                let mut parts = v.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some("Bearer"), Some(t)) => Some(t.trim()),
+                    _ => None,
+                }
+            });
+        let token = match presented.and_then(|t| tokens.find(t)) {
+            Some(t) => t,
+            None => {
+                let resp = HttpResponse::Unauthorized()
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .json(ApiError::new("a valid bearer token is required"));
+                return Ok(self.reject(req, resp));
+            }
+        };
+        req.extensions_mut().insert(Caller(token.name.clone()));
+
+        if token.role < required_role(req.method()) {
+            let resp =
+                HttpResponse::Forbidden().json(ApiError::new("this token may not change anything"));
+            return Ok(self.reject(req, resp));
+        }
+
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<S>, resp: HttpResponse) -> actix_web::Result<Response> {
+        if required_role(req.method()) == Role::Admin {
+            self.audit(req, &resp);
+        }
+
+        Ok(Response::Done(resp))
+    }
+}
+
+// A Unix domain socket connection that hands the credentials of its peer to
+// every request served over it.
+#[cfg(not(target_os = "windows"))]
+pub struct PeerStream {
+    stream: tokio_uds::UnixStream,
+    extensions: std::rc::Rc<actix_web::Extensions>,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl PeerStream {
+    pub fn new(stream: tokio_uds::UnixStream) -> Self {
+        let mut extensions = actix_web::Extensions::new();
+        if let Ok(cred) = stream.peer_cred() {
+            extensions.insert(PeerCred {
+                uid: cred.uid,
+                gid: cred.gid,
+            });
+        }
+
+        Self {
+            stream,
+            extensions: std::rc::Rc::new(extensions),
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl tokio_io::AsyncRead for PeerStream {}
+
+#[cfg(not(target_os = "windows"))]
+impl tokio_io::AsyncWrite for PeerStream {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        tokio_io::AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl actix_web::server::IoStream for PeerStream {
+    fn shutdown(&mut self, how: std::net::Shutdown) -> std::io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn set_nodelay(&mut self, _: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_linger(&mut self, _: Option<std::time::Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_keepalive(&mut self, _: Option<std::time::Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn extensions(&self) -> Option<std::rc::Rc<actix_web::Extensions>> {
+        Some(self.extensions.clone())
+    }
+}
+
+mod test {
+    #[test]
+    fn parses_tokens_and_roles() {
+        use super::{Role, Tokens};
+
+        let tokens = Tokens::parse(
+            "- name: monitoring\n  role: read\n  token: 0123456789abcdef\n\
+             - name: deploy\n  role: admin\n  token: fedcba9876543210\n",
+        )
+        .unwrap();
+
+        assert_eq!(tokens.find("0123456789abcdef").unwrap().role, Role::Read);
+        assert_eq!(tokens.find("fedcba9876543210").unwrap().name, "deploy");
+        assert!(tokens.find("0123456789abcdeX").is_none());
+        assert!(Role::Admin > Role::Read);
+        assert!(Tokens::parse("- name: weak\n  role: admin\n  token: secret\n").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+// Number of chunks a subscriber may fall behind by before it is disconnected.
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+// Fans chunks of bytes out to any number of subscribers, e.g. HTTP clients
+// following the output of a run. Subscribers that cannot keep up are dropped
+// rather than buffered without bound.
+//
+// A broadcast is either open or closed. Closing it ends the stream of every
+// subscriber, and subscribing to a closed broadcast yields a stream that ends
+// right away.
+pub struct Broadcast {
+    subscribers: Mutex<Option<Vec<Sender<Bytes>>>>,
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Broadcast {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(None),
+        }
+    }
+
+    pub fn open(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        *subscribers = Some(Vec::new());
+    }
+
+    pub fn close(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        *subscribers = None;
+    }
+
+    // `f` runs while nothing can be published, so whatever it observes, e.g.
+    // the length of a log file, lines up exactly with where the stream starts.
+    pub fn subscribe<F, R>(&self, f: F) -> (R, Receiver<Bytes>)
+    where
+        F: FnOnce() -> R,
+    {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let (tx, rx) = channel(SUBSCRIBER_BACKLOG);
+        let r = f();
+
+        if let Some(ref mut v) = *subscribers {
+            v.push(tx);
+        }
+
+        (r, rx)
+    }
+
+    // Hands `chunk` to `f`, e.g. to write it to a log file, and then to every
+    // subscriber.
+    pub fn publish<F, R>(&self, chunk: Bytes, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let r = f(&chunk);
+
+        if let Some(ref mut v) = *subscribers {
+            let mut i = 0;
+            while i < v.len() {
+                if v[i].try_send(chunk.clone()).is_err() {
+                    v.swap_remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        r
+    }
+}
+
+mod test {
+    #[test]
+    fn delivers_chunks_published_after_subscribing() {
+        use super::Broadcast;
+        use futures::{Future, Stream};
+
+        let b = Broadcast::new();
+        b.open();
+        b.publish("before\n".into(), |_| ());
+        let ((), rx) = b.subscribe(|| ());
+        b.publish("after\n".into(), |_| ());
+        b.close();
+
+        let chunks = rx.collect().wait().unwrap();
+
+        assert_eq!(chunks, vec!["after\n"]);
+    }
+
+    #[test]
+    fn closed_broadcast_ends_streams() {
+        use super::Broadcast;
+        use futures::{Future, Stream};
+
+        let b = Broadcast::new();
+        let ((), rx) = b.subscribe(|| ());
+        b.publish("dropped\n".into(), |_| ());
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+}
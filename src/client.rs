@@ -0,0 +1,385 @@
+use crate::config::{ApiConfig, ConfigError, Listen, TlsConfig};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+// Environment variable holding the bearer token sent with every request.
+pub const TOKEN_ENV: &str = "CHEFCTL_TOKEN";
+
+// How long to wait for the daemon before deciding nobody is listening.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long a daemon that accepted the connection may take to answer.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct ClientError(String);
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "client error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> ClientError {
+        match e.kind() {
+            // How the timeouts above expiring are reported.
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                ClientError("the daemon did not answer in time".into())
+            }
+            _ => ClientError(e.to_string()),
+        }
+    }
+}
+
+impl From<ConfigError> for ClientError {
+    fn from(e: ConfigError) -> ClientError {
+        ClientError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> ClientError {
+        ClientError(e.to_string())
+    }
+}
+
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+// A minimal HTTP/1.1 client for the API of the local daemon, speaking to
+// whatever `api.listen` in the config points at.
+#[derive(Debug)]
+pub struct Client {
+    listen: Listen,
+    tls: Option<TlsConfig>,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn from_config(config: &ApiConfig) -> Result<Self, ClientError> {
+        let listen = config.listen()?;
+        if listen == Listen::Disabled {
+            return Err(ClientError("the api is disabled".into()));
+        }
+
+        Ok(Self {
+            listen,
+            tls: config.tls.clone(),
+            token: std::env::var(TOKEN_ENV).ok(),
+        })
+    }
+
+    // Where the daemon is expected, for messages.
+    pub fn address(&self) -> String {
+        match self.listen {
+            Listen::Tcp(ref addr) => addr.clone(),
+            Listen::Unix(ref path) => path.display().to_string(),
+            Listen::Disabled => "nowhere".into(),
+        }
+    }
+
+    fn connect(&self) -> Result<Box<dyn Connection>, ClientError> {
+        match self.listen {
+            Listen::Tcp(ref addr) => {
+                let addr = local_address(addr);
+                let stream = match std::net::ToSocketAddrs::to_socket_addrs(&addr)?.next() {
+                    Some(a) => TcpStream::connect_timeout(&a, CONNECT_TIMEOUT)?,
+                    None => return Err(ClientError(format!("{} does not resolve", addr))),
+                };
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+                match self.tls {
+                    Some(ref tls) => connect_tls(tls, stream),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            Listen::Unix(ref path) => connect_unix(path),
+            Listen::Disabled => Err(ClientError("the api is disabled".into())),
+        }
+    }
+
+    // Sends a request without a body. Responses are returned whatever their
+    // status, the body is read as it arrives.
+    pub fn request(&self, method: &str, path: &str) -> Result<Response, ClientError> {
+        let mut conn = self.connect()?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 0\r\n",
+            method, path
+        );
+        if let Some(ref token) = self.token {
+            head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        head.push_str("\r\n");
+        conn.write_all(head.as_bytes())?;
+        conn.flush()?;
+
+        Response::read(BufReader::new(conn))
+    }
+
+    pub fn get(&self, path: &str) -> Result<Response, ClientError> {
+        self.request("GET", path)
+    }
+}
+
+// Listeners bound to every address are reached through loopback.
+fn local_address(addr: &str) -> String {
+    if addr.starts_with("0.0.0.0:") {
+        addr.replacen("0.0.0.0", "127.0.0.1", 1)
+    } else if addr.starts_with("[::]:") {
+        addr.replacen("[::]", "[::1]", 1)
+    } else {
+        addr.to_string()
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn connect_unix(path: &std::path::Path) -> Result<Box<dyn Connection>, ClientError> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    Ok(Box::new(stream))
+}
+
+#[cfg(target_os = "windows")]
+fn connect_unix(_: &std::path::Path) -> Result<Box<dyn Connection>, ClientError> {
+    Err(ClientError(
+        "unix domain sockets are not supported on windows".into(),
+    ))
+}
+
+// Only the daemon's own certificate, the first one in `api.tls.cert`, is
+// trusted: it is pinned, whoever issued it. The host name is not verified,
+// the address comes from the same config as the certificate. The client
+// certificate is presented when one is configured.
+#[cfg(not(target_os = "windows"))]
+fn connect_tls(tls: &TlsConfig, stream: TcpStream) -> Result<Box<dyn Connection>, ClientError> {
+    use openssl::{
+        ssl::{SslConnector, SslFiletype, SslMethod},
+        x509::{verify::X509VerifyFlags, X509},
+    };
+
+    let tls_error = |e: openssl::error::ErrorStack| ClientError(e.to_string());
+    let load_error = |path: &str, e: openssl::error::ErrorStack| {
+        ClientError(format!("could not load \"{}\": {}", path, e))
+    };
+    let pem = std::fs::read(&tls.cert)
+        .map_err(|e| ClientError(format!("could not load \"{}\": {}", tls.cert, e)))?;
+    let pinned = X509::stack_from_pem(&pem)
+        .map_err(|e| load_error(&tls.cert, e))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ClientError(format!("\"{}\" holds no certificate", tls.cert)))?;
+    let pinned_der = pinned.to_der().map_err(tls_error)?;
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+    // Nothing but the pinned certificate is in the store, the chain ends
+    // there.
+    builder
+        .cert_store_mut()
+        .add_cert(pinned)
+        .map_err(tls_error)?;
+    builder
+        .cert_store_mut()
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(tls_error)?;
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(|e| load_error(cert, e))?;
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(|e| load_error(key, e))?;
+            builder.check_private_key().map_err(tls_error)?;
+        }
+        (None, None) => {}
+        _ => {
+            return Err(ClientError(
+                "api.tls.client_cert and api.tls.client_key go together".into(),
+            ))
+        }
+    }
+    let config = builder
+        .build()
+        .configure()
+        .map_err(tls_error)?
+        .verify_hostname(false);
+
+    let stream = config
+        .connect("localhost", stream)
+        .map_err(|e| ClientError(format!("tls handshake failed: {}", e)))?;
+
+    // The token must not go anywhere else.
+    let presented = match stream.ssl().peer_certificate() {
+        Some(c) => c.to_der().map_err(tls_error)?,
+        None => Vec::new(),
+    };
+    if presented != pinned_der {
+        return Err(ClientError(format!(
+            "the daemon did not present the certificate in \"{}\"",
+            tls.cert
+        )));
+    }
+
+    Ok(Box::new(stream))
+}
+
+#[cfg(target_os = "windows")]
+fn connect_tls(_: &TlsConfig, _: TcpStream) -> Result<Box<dyn Connection>, ClientError> {
+    Err(ClientError("tls is not supported on windows".into()))
+}
+
+pub struct Response {
+    pub status: u16,
+    body: Box<dyn BufRead>,
+}
+
+impl Response {
+    fn read<R: BufRead + 'static>(mut conn: R) -> Result<Self, ClientError> {
+        let mut line = String::new();
+        conn.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| ClientError(format!("not an http response: {:?}", line.trim())))?;
+
+        let mut chunked = false;
+        loop {
+            line.clear();
+            if conn.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.trim().eq_ignore_ascii_case("transfer-encoding")
+                    && value.trim().eq_ignore_ascii_case("chunked")
+                {
+                    chunked = true;
+                }
+            }
+        }
+
+        let body: Box<dyn BufRead> = if chunked {
+            Box::new(BufReader::new(Chunked::new(conn)))
+        } else {
+            Box::new(conn)
+        };
+
+        Ok(Self { status, body })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    // The body as it arrives, e.g. for streamed responses.
+    pub fn body(&mut self) -> &mut dyn BufRead {
+        &mut *self.body
+    }
+
+    pub fn text(mut self) -> Result<String, ClientError> {
+        let mut body = String::new();
+        self.body.read_to_string(&mut body)?;
+
+        Ok(body)
+    }
+
+    // Turns an unexpected response into an error carrying what the API said.
+    pub fn into_error(self) -> ClientError {
+        let status = self.status;
+        let message = self
+            .json::<serde_json::Value>()
+            .ok()
+            .and_then(|v| v["error"].as_str().map(String::from))
+            .unwrap_or_else(|| "no details".into());
+
+        ClientError(format!("the daemon answered {}: {}", status, message))
+    }
+
+    pub fn json<T: serde::de::DeserializeOwned>(self) -> Result<T, ClientError> {
+        Ok(serde_json::from_str(&self.text()?)?)
+    }
+}
+
+// Decodes a body sent with chunked transfer encoding.
+struct Chunked<R> {
+    inner: R,
+    // Bytes left in the current chunk, `None` once the last one was read.
+    remaining: Option<usize>,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: Some(0),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = match self.remaining {
+            None => return Ok(0),
+            Some(0) => {
+                let mut size = String::new();
+                self.inner.read_line(&mut size)?;
+                if size.trim().is_empty() {
+                    // The line ending the previous chunk.
+                    size.clear();
+                    self.inner.read_line(&mut size)?;
+                }
+                let size = size.trim().split(';').next().unwrap_or("");
+                let size = usize::from_str_radix(size, 16).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid chunk size {:?}", size),
+                    )
+                })?;
+                if size == 0 {
+                    self.remaining = None;
+                    return Ok(0);
+                }
+
+                size
+            }
+            Some(n) => n,
+        };
+
+        let len = remaining.min(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining = Some(remaining - n);
+
+        Ok(n)
+    }
+}
+
+mod test {
+    #[test]
+    fn decodes_chunked_responses() {
+        use super::Response;
+        use std::io::Cursor;
+
+        let raw = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let resp = Response::read(Cursor::new(raw.as_bytes().to_vec())).unwrap();
+
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text().unwrap(), "hello, world");
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{
+    config::{OutputConfig, CONFIG},
+    retention::{link_targets, run_logs},
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    ffi::OsStr,
+    fs::{File, Metadata},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+};
+
+// Appended to the name of a log once it is compressed.
+pub const SUFFIX: &str = ".gz";
+
+// A log is compressed into this first so a half written one is never taken
+// for the real thing.
+const PARTIAL_SUFFIX: &str = ".gz.partial";
+
+// Set while a worker is compressing logs.
+static COMPRESSING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // The compression started after the last run.
+    static ref WORKER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
+
+pub fn compressed_path(path: &Path) -> PathBuf {
+    with_suffix(path, SUFFIX)
+}
+
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(&SUFFIX[1..]))
+}
+
+// Where the log written to `path` is now, which is its compressed version once
+// it has been compressed.
+pub fn locate(path: &Path) -> PathBuf {
+    let compressed = compressed_path(path);
+    if !path.exists() && compressed.exists() {
+        return compressed;
+    }
+
+    path.to_path_buf()
+}
+
+// A log as `open` finds it.
+pub enum Source {
+    Plain(File),
+    // The log of an older run, compressed since.
+    Compressed(Box<GzDecoder<File>>),
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Source::Plain(f) => f.read(buf),
+            Source::Compressed(f) => f.read(buf),
+        }
+    }
+}
+
+// Opens the log written to `path` for reading, decompressing it on the fly
+// when it has been compressed since.
+pub fn open(path: &Path) -> std::io::Result<Source> {
+    let path = locate(path);
+    let file = File::open(&path)?;
+
+    if is_compressed(&path) {
+        Ok(Source::Compressed(Box::new(GzDecoder::new(file))))
+    } else {
+        Ok(Source::Plain(file))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(target_os = "windows")]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    File::create(path)
+}
+
+// Retention goes by the modification time, which has to stay the one of the
+// run.
+#[cfg(not(target_os = "windows"))]
+fn copy_times(path: &Path, from: &Metadata) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::MetadataExt};
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timeval {
+            tv_sec: from.atime() as libc::time_t,
+            tv_usec: 0,
+        },
+        libc::timeval {
+            tv_sec: from.mtime() as libc::time_t,
+            tv_usec: 0,
+        },
+    ];
+
+    match unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn copy_times(_: &Path, _: &Metadata) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn write_compressed(path: &Path, to: &Path, meta: &Metadata) -> std::io::Result<()> {
+    let mut log = File::open(path)?;
+    let out = create_private(to)?;
+    out.set_permissions(meta.permissions())?;
+
+    let mut encoder = GzEncoder::new(BufWriter::new(out), Compression::default());
+    std::io::copy(&mut log, &mut encoder)?;
+    encoder.finish()?.flush()?;
+
+    copy_times(to, meta)
+}
+
+// Replaces a log that is not written to anymore with its compressed version,
+// which keeps its permissions and modification time.
+pub fn compress(path: &Path) -> std::io::Result<PathBuf> {
+    let meta = std::fs::metadata(path)?;
+    let partial = with_suffix(path, PARTIAL_SUFFIX);
+    if let Err(e) = write_compressed(path, &partial, &meta) {
+        let _ = std::fs::remove_file(&partial);
+
+        return Err(e);
+    }
+
+    let compressed = compressed_path(path);
+    std::fs::rename(&partial, &compressed)?;
+    match std::fs::remove_file(path) {
+        // Retention got to it first.
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+        Ok(()) => {}
+    }
+
+    Ok(compressed)
+}
+
+// Compresses every log in the output directory except the ones behind the
+// symlinks, which are still read as they are. Returns the compressed logs.
+pub fn compress_logs(output: &OutputConfig) -> std::io::Result<Vec<PathBuf>> {
+    let keep = link_targets(output);
+    let mut compressed = Vec::new();
+
+    for log in run_logs(output)? {
+        if is_compressed(&log.path) || keep.contains(&log.path) {
+            continue;
+        }
+
+        match compress(&log.path) {
+            Ok(path) => compressed.push(path),
+            Err(e) => eprintln!("could not compress \"{}\": {}", log.path.display(), e),
+        }
+    }
+
+    Ok(compressed)
+}
+
+// Compresses the logs of older runs on another thread, if `output.compress`
+// is set. Logs of debug runs can take a while.
+pub fn compress_in_background() {
+    let output = CONFIG.read().unwrap().output.clone();
+    if !output.compress {
+        return;
+    }
+
+    // Two of them must never work on the same log, and the run that is
+    // about to start must not wait for the last one. Whatever it leaves is
+    // picked up after the next run.
+    if COMPRESSING.swap(true, Ordering::SeqCst) {
+        eprintln!("still compressing the logs of older runs, skipping");
+        return;
+    }
+
+    let mut worker = WORKER.lock().unwrap();
+    // Done already, it cleared `COMPRESSING`.
+    if let Some(previous) = worker.take() {
+        let _ = previous.join();
+    }
+
+    *worker = Some(std::thread::spawn(move || {
+        if let Err(e) = compress_logs(&output) {
+            eprintln!("could not compress logs in \"{}\": {}", output.dir, e);
+        }
+        COMPRESSING.store(false, Ordering::SeqCst);
+    }));
+}
+
+// Waits for the compression started after the last run, e.g. before a
+// one-shot chefctl exits.
+pub fn wait() {
+    let worker = WORKER.lock().unwrap().take();
+    if let Some(w) = worker {
+        let _ = w.join();
+    }
+}
+
+mod test {
+    #[test]
+    fn reads_compressed_logs_transparently() {
+        use super::{compress, open};
+        use std::io::{Read, Write};
+
+        let dir = std::env::temp_dir().join(format!("chefctl-compress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("chef.20190115T103000-5f2a9c1e.out");
+        let content = "Starting Chef Client\n".repeat(1000);
+        std::fs::File::create(&log)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+
+        let compressed = compress(&log).unwrap();
+        let mut read = String::new();
+        open(&log).unwrap().read_to_string(&mut read).unwrap();
+
+        assert!(!log.exists());
+        assert!(std::fs::metadata(&compressed).unwrap().len() < content.len() as u64);
+        assert_eq!(read, content);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
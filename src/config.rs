@@ -0,0 +1,490 @@
+use crate::platform::{hostname, AUDIT_LOG_PATH, HISTORY_PATH, OUTPUT_DIR};
+use chrono::prelude::Local;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "config error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> ConfigError {
+        ConfigError(e.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(e: serde_yaml::Error) -> ConfigError {
+        ConfigError(e.to_string())
+    }
+}
+
+// Settings read from `chefctl.yml`. Every section is optional so that an empty
+// or missing file behaves exactly like the defaults below.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub api: ApiConfig,
+    pub daemon: DaemonConfig,
+    pub history: HistoryConfig,
+    pub metrics: MetricsConfig,
+    pub output: OutputConfig,
+}
+
+// Where the REST API listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+    Disabled,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    // One of `tcp:<address>:<port>`, `unix:<path>` or `none`. A bare address
+    // is taken as TCP and a bare absolute path as a Unix domain socket.
+    pub listen: String,
+    // User and group owning the Unix domain socket, by name or id.
+    pub socket_owner: Option<String>,
+    pub socket_group: Option<String>,
+    // Octal permissions of the Unix domain socket.
+    pub socket_mode: String,
+    // File with the tokens callers must present, readable by root only. When
+    // this is not set anyone who can reach the API may read, but only root on
+    // the Unix domain socket may start or cancel runs.
+    pub tokens_file: Option<String>,
+    // Every request that changes something is appended to this file.
+    pub audit_log: String,
+    // Serve HTTPS instead of HTTP, required unless the API only listens on
+    // a loopback address.
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    // PEM encoded certificate chain, starting with the server's certificate.
+    pub cert: String,
+    // PEM encoded private key of the certificate.
+    pub key: String,
+    // PEM bundle of the CAs client certificates must be signed by. Clients
+    // need no certificate when this is not set.
+    pub client_ca: Option<String>,
+    // PEM encoded certificate and key the chefctl commands talking to the
+    // daemon, e.g. `status`, present when `client_ca` is set.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            listen: "tcp:127.0.0.1:6666".into(),
+            socket_owner: None,
+            socket_group: None,
+            socket_mode: "0600".into(),
+            tokens_file: None,
+            audit_log: AUDIT_LOG_PATH.into(),
+            tls: None,
+        }
+    }
+}
+
+impl ApiConfig {
+    pub fn listen(&self) -> Result<Listen, ConfigError> {
+        let l = self.listen.trim();
+        let (scheme, rest) = match l.find(':') {
+            Some(i) => (&l[..i], &l[i + 1..]),
+            None => ("", l),
+        };
+
+        if l.is_empty() || l == "none" || l == "disabled" {
+            Ok(Listen::Disabled)
+        } else if scheme == "unix" {
+            Ok(Listen::Unix(PathBuf::from(rest)))
+        } else if scheme == "tcp" {
+            Ok(Listen::Tcp(rest.to_string()))
+        } else if l.starts_with('/') {
+            Ok(Listen::Unix(PathBuf::from(l)))
+        } else if l.contains(':') {
+            Ok(Listen::Tcp(l.to_string()))
+        } else {
+            Err(ConfigError(format!(
+                "\"{}\" is not a valid api listener",
+                l
+            )))
+        }
+    }
+
+    pub fn socket_mode(&self) -> Result<u32, ConfigError> {
+        u32::from_str_radix(self.socket_mode.trim_start_matches("0o"), 8)
+            .map_err(|_| ConfigError(format!("\"{}\" is not an octal mode", self.socket_mode)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    // Seconds between the start of two consecutive runs.
+    pub interval: u64,
+    // Maximum number of seconds a run is delayed by before `chef-client` starts.
+    pub splay: u32,
+    // Cron-style expressions, e.g. `*/30 * * * *`. When present these replace
+    // `interval` and runs are splayed within each slot.
+    pub schedule: Vec<String>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            interval: 1800,
+            splay: 870,
+            schedule: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    // JSON lines file every finished run is appended to.
+    pub path: String,
+    // Older runs are dropped once there are more than this many.
+    pub max_entries: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            path: HISTORY_PATH.into(),
+            max_entries: 1000,
+        }
+    }
+}
+
+// Where the output of every run is kept.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    // Created readable by its owner and group only when it does not exist.
+    pub dir: String,
+    // Name of the log of a run. `{run_id}`, `{timestamp}` and `{hostname}`
+    // are replaced, `{run_id}` is required so that runs never share a log.
+    pub file_name: String,
+    // Symlinks to the logs of the current and the previous run, relative to
+    // `dir` unless absolute.
+    pub current_link: String,
+    pub last_link: String,
+    pub retention: RetentionConfig,
+    // Gzip the logs of older runs in the background after every run.
+    pub compress: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            dir: OUTPUT_DIR.into(),
+            file_name: "chef.{run_id}.out".into(),
+            current_link: "chef.cur.out".into(),
+            last_link: "chef.last.out".into(),
+            retention: RetentionConfig::default(),
+            compress: false,
+        }
+    }
+}
+
+// Which run logs to delete after every run. Every limit is optional and logs
+// are kept forever without any. The logs behind the symlinks are never
+// deleted.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // Number of most recent logs to keep.
+    pub keep_last: Option<usize>,
+    // Seconds after which a log is deleted.
+    pub max_age: Option<u64>,
+    // Older logs are deleted once all of them take up more than this.
+    pub max_total_bytes: Option<u64>,
+    // Seconds the logs of failed runs are kept for regardless of the limits
+    // above.
+    pub keep_failures_for: Option<u64>,
+}
+
+impl OutputConfig {
+    // Fills in the placeholders of `file_name`.
+    pub fn render_file_name(
+        &self,
+        run_id: &str,
+        timestamp: &str,
+        hostname: &str,
+    ) -> Result<String, ConfigError> {
+        let invalid = |why: &str| {
+            ConfigError(format!(
+                "\"{}\" is not a valid output file name, {}",
+                self.file_name, why
+            ))
+        };
+        if !self.file_name.contains("{run_id}") {
+            return Err(invalid("it needs to contain {run_id}"));
+        }
+
+        let mut name = String::new();
+        let mut rest = self.file_name.as_str();
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("a { is never closed"))?;
+            name.push_str(match &rest[start + 1..start + end] {
+                "run_id" => run_id,
+                "timestamp" => timestamp,
+                "hostname" => hostname,
+                p => return Err(invalid(&format!("{{{}}} is not a known placeholder", p))),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        name.push_str(rest);
+
+        if name.contains('/') || name.contains('\\') {
+            return Err(invalid("it may not contain directories"));
+        }
+
+        Ok(name)
+    }
+
+    pub fn log_path(&self, run_id: &str) -> Result<PathBuf, ConfigError> {
+        let timestamp = Local::now().format("%Y%m%dT%H%M%S").to_string();
+        let name = self.render_file_name(run_id, &timestamp, &hostname())?;
+
+        Ok(Path::new(&self.dir).join(name))
+    }
+
+    // Whether `name` could have been rendered from `file_name`, compressed or
+    // not.
+    pub fn is_log_name(&self, name: &str) -> bool {
+        let mut pattern = String::from("^");
+        let mut rest = self.file_name.as_str();
+        while let Some(start) = rest.find('{') {
+            pattern.push_str(&regex::escape(&rest[..start]));
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return false,
+            };
+            // What `log_path` puts in, so that nothing else in the output
+            // directory is ever taken for a log.
+            pattern.push_str(match &rest[start + 1..end] {
+                "run_id" => r"\d{8}T\d{6}-[0-9a-f]{8}",
+                "timestamp" => r"\d{8}T\d{6}",
+                "hostname" => r"[0-9A-Za-z._-]+",
+                _ => return false,
+            });
+            rest = &rest[end + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push_str(&format!("({})?$", regex::escape(crate::compress::SUFFIX)));
+
+        regex::Regex::new(&pattern)
+            .map(|r| r.is_match(name))
+            .unwrap_or(false)
+    }
+
+    pub fn current_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.current_link)
+    }
+
+    pub fn last_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.last_link)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // Directory watched by node_exporter's textfile collector. `chefctl.prom`
+    // is rewritten there after every run and health check update.
+    pub textfile_dir: Option<String>,
+    pub statsd: Option<StatsdConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatsdConfig {
+    // Where the local statsd agent listens, e.g. `127.0.0.1:8125`.
+    pub address: String,
+    pub prefix: String,
+    // Send DogStatsD tags. Plain statsd has no tags, so the outcome becomes
+    // part of the metric name instead and the other tags are dropped.
+    pub dogstatsd: bool,
+    // Added to every metric, e.g. `role: web`. `host` is always added.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8125".into(),
+            prefix: "chefctl".into(),
+            dogstatsd: true,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        let config: Self = serde_yaml::from_str(content)?;
+        config.output.render_file_name("", "", "")?;
+
+        Ok(config)
+    }
+
+    // A missing config file is not an error, the defaults are used instead.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let mut file = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        Self::parse(&content)
+    }
+}
+
+lazy_static! {
+    pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+}
+
+// Replaces the global configuration with the contents of `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(), ConfigError> {
+    let config = Config::from_path(path)?;
+    let mut val = CONFIG.write().unwrap();
+
+    *val = config;
+
+    Ok(())
+}
+
+mod test {
+    #[test]
+    fn empty_config_uses_defaults() {
+        use super::Config;
+
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(config.daemon.interval, 1800);
+        assert_eq!(config.daemon.splay, 870);
+    }
+
+    #[test]
+    fn parses_daemon_section() {
+        use super::Config;
+
+        let config = Config::parse("daemon:\n  interval: 600\n").unwrap();
+
+        assert_eq!(config.daemon.interval, 600);
+        assert_eq!(config.daemon.splay, 870);
+    }
+
+    #[test]
+    fn parses_api_listeners() {
+        use super::{Config, Listen};
+        use std::path::PathBuf;
+
+        let listen = |l: &str| {
+            let config = Config::parse(&format!("api:\n  listen: \"{}\"\n", l)).unwrap();
+            config.api.listen().unwrap()
+        };
+
+        assert_eq!(
+            listen("tcp:0.0.0.0:6666"),
+            Listen::Tcp("0.0.0.0:6666".into())
+        );
+        assert_eq!(
+            listen("127.0.0.1:6666"),
+            Listen::Tcp("127.0.0.1:6666".into())
+        );
+        assert_eq!(
+            listen("unix:/run/chefctl.sock"),
+            Listen::Unix(PathBuf::from("/run/chefctl.sock"))
+        );
+        assert_eq!(
+            listen("/run/chefctl.sock"),
+            Listen::Unix(PathBuf::from("/run/chefctl.sock"))
+        );
+        assert_eq!(listen("none"), Listen::Disabled);
+        assert_eq!(Config::default().api.socket_mode().unwrap(), 0o600);
+    }
+
+    #[test]
+    fn parses_tls_section() {
+        use super::Config;
+
+        let config = Config::parse(
+            "api:\n  listen: 0.0.0.0:6666\n  tls:\n    cert: /etc/chefctl/cert.pem\n    key: /etc/chefctl/key.pem\n",
+        )
+        .unwrap();
+        let tls = config.api.tls.unwrap();
+
+        assert_eq!(tls.cert, "/etc/chefctl/cert.pem");
+        assert_eq!(tls.key, "/etc/chefctl/key.pem");
+        assert_eq!(tls.client_ca, None);
+        assert!(Config::parse("api:\n  tls:\n    cert: /etc/chefctl/cert.pem\n").is_err());
+    }
+
+    #[test]
+    fn parses_schedule_list() {
+        use super::Config;
+
+        let config =
+            Config::parse("daemon:\n  schedule:\n    - \"*/30 * * * *\"\n    - \"0 2 * * *\"\n")
+                .unwrap();
+
+        assert_eq!(config.daemon.schedule, vec!["*/30 * * * *", "0 2 * * *"]);
+    }
+
+    #[test]
+    fn renders_output_file_names() {
+        use super::Config;
+
+        let config = Config::parse(
+            "output:\n  dir: /var/log/chef\n  file_name: \"{hostname}.{timestamp}.{run_id}.log\"\n",
+        )
+        .unwrap();
+        let name = config
+            .output
+            .render_file_name("20190115T103000-5f2a9c1e", "20190115T103000", "web1")
+            .unwrap();
+
+        assert_eq!(name, "web1.20190115T103000.20190115T103000-5f2a9c1e.log");
+        assert!(config.output.is_log_name(&name));
+        assert!(config.output.is_log_name(&format!("{}.gz", name)));
+        assert!(!config.output.is_log_name("chef.cur.out"));
+        assert!(!config.output.is_log_name("web1.20190115T103000.backup.log"));
+        assert!(!Config::default().output.is_log_name("chef.last.out"));
+        assert_eq!(
+            config.output.current_path().to_str(),
+            Some("/var/log/chef/chef.cur.out")
+        );
+        assert!(Config::parse("output:\n  file_name: chef.{timestamp}.out\n").is_err());
+        assert!(Config::parse("output:\n  file_name: \"{run_id}.{pid}\"\n").is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{
+    health::update_health_checks,
+    process::{converge, new_run_id, Finished, RunOptions, Trigger},
+    schedule::Schedule,
+    state::APP_STATE,
+};
+use chrono::prelude::{DateTime, Local};
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+// How long to sleep for at a time when nothing is scheduled.
+const IDLE_WAIT: Duration = Duration::from_secs(3600);
+
+// A request for a run outside of the regular schedule.
+#[derive(Debug, Default, Clone)]
+pub struct RunRequest {
+    pub run_id: String,
+    // Start `chef-client` without waiting for a splay first.
+    pub skip_splay: bool,
+    pub why_run: bool,
+    // Replaces the node's run list for this run only.
+    pub run_list: Option<String>,
+}
+
+impl RunRequest {
+    pub fn new() -> Self {
+        Self {
+            run_id: new_run_id(),
+            ..Default::default()
+        }
+    }
+
+    // Whether both requests would run `chef-client` the same way.
+    fn same_options(&self, other: &RunRequest) -> bool {
+        self.skip_splay == other.skip_splay
+            && self.why_run == other.why_run
+            && self.run_list == other.run_list
+    }
+
+    // Appends the options of this request to a rendered `chef-client` command line.
+    fn apply(&self, args: &str) -> String {
+        let mut args = args.to_string();
+
+        if self.why_run && !args.contains("--why-run") {
+            args.push_str(" --why-run");
+        }
+        if let Some(ref run_list) = self.run_list {
+            args.push_str(&format!(" -o {}", run_list));
+        }
+
+        args
+    }
+}
+
+// The outcome of handing a `RunRequest` to the `RunQueue`.
+#[derive(Debug, PartialEq)]
+pub enum Submitted {
+    // The request will be picked up by the daemon, carries the run id.
+    Accepted(String),
+    // A run is converging and the request asked not to be queued, carries the
+    // id of the run in progress.
+    Busy(String),
+    // A run with other options is already queued, carries its id.
+    Conflicting(String),
+    // Nothing is consuming requests, i.e. chefctl is not running as a daemon.
+    Unavailable,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    accepting: bool,
+    // The id of the run going through the state machine, if any.
+    current: Option<String>,
+    pending: Option<RunRequest>,
+}
+
+// Hands run requests over to the daemon loop. Only a single request is ever
+// kept, so requests that arrive while a run is converging are coalesced into
+// one follow-up run that starts as soon as the current one has finished.
+// Requests are only coalesced when their options are the same, otherwise the
+// queued one wins and the later one is refused.
+pub struct RunQueue {
+    inner: Mutex<Queue>,
+    cond: Condvar,
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        Self {
+            inner: Mutex::new(Queue::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn submit(&self, req: RunRequest, queue: bool) -> Submitted {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !inner.accepting {
+            return Submitted::Unavailable;
+        }
+        if let Some(ref current) = inner.current {
+            if !queue {
+                return Submitted::Busy(current.clone());
+            }
+        }
+        if let Some(ref pending) = inner.pending {
+            if !pending.same_options(&req) {
+                return Submitted::Conflicting(pending.run_id.clone());
+            }
+
+            return Submitted::Accepted(pending.run_id.clone());
+        }
+
+        let run_id = req.run_id.clone();
+        inner.pending = Some(req);
+        self.cond.notify_one();
+
+        Submitted::Accepted(run_id)
+    }
+
+    // Blocks until a run is requested or `timeout` has elapsed.
+    fn wait(&self, timeout: Duration) -> Option<RunRequest> {
+        let inner = self.inner.lock().unwrap();
+        let (mut inner, _) = self
+            .cond
+            .wait_timeout_while(inner, timeout, |q| q.pending.is_none())
+            .unwrap();
+
+        inner.pending.take()
+    }
+
+    // The id of the run going through the state machine, if any.
+    pub fn current(&self) -> Option<String> {
+        self.inner.lock().unwrap().current.clone()
+    }
+
+    fn open(&self) {
+        self.inner.lock().unwrap().accepting = true;
+    }
+
+    fn set_current(&self, run_id: Option<String>) {
+        self.inner.lock().unwrap().current = run_id;
+    }
+}
+
+lazy_static! {
+    pub static ref RUN_QUEUE: RunQueue = RunQueue::new();
+}
+
+// Keeps converging according to `schedule` for as long as the process is alive.
+// The REST API keeps running on its own thread in between runs.
+#[derive(Debug)]
+pub struct Daemon {
+    args: String,
+    schedule: Schedule,
+    splay: u32,
+}
+
+impl Daemon {
+    pub fn new(args: String, schedule: Schedule, splay: u32) -> Self {
+        Self {
+            args,
+            schedule,
+            splay,
+        }
+    }
+
+    // Runs jitter within their slot, so the splay never reaches into the next one.
+    fn splay_within(&self, slot: DateTime<Local>, next: Option<DateTime<Local>>) -> u32 {
+        match next.map(|n| (n - slot).num_seconds()) {
+            Some(s) if s >= 0 && s < i64::from(self.splay) => s as u32,
+            _ => self.splay,
+        }
+    }
+
+    pub fn run(&self) {
+        RUN_QUEUE.open();
+
+        // Interval schedules converge right away, cron schedules wait for the
+        // first slot. Health is checked before every run, so with cron it is
+        // checked up front as well rather than unknown until the first slot.
+        let mut next_run = match self.schedule {
+            Schedule::Interval(_) => Some(Local::now()),
+            Schedule::Cron(_) => {
+                std::thread::spawn(update_health_checks);
+
+                self.schedule.next_after(Local::now())
+            }
+        };
+
+        loop {
+            APP_STATE.update_next_run(next_run.map(|t| t.to_rfc3339()));
+
+            let request = match next_run {
+                Some(t) => match (t - Local::now()).to_std() {
+                    Ok(d) => RUN_QUEUE.wait(d),
+                    Err(_) => None,
+                },
+                None => RUN_QUEUE.wait(IDLE_WAIT),
+            };
+
+            let started = Local::now();
+            let slot = match next_run {
+                Some(t) if t <= started => Some(t),
+                _ => None,
+            };
+
+            // A requested run takes precedence, the slot it coincides with
+            // counts as done.
+            let (req, splay, trigger) = match (request, slot) {
+                (Some(req), slot) => {
+                    if slot.is_some() {
+                        next_run = self.schedule.next_after(started);
+                    }
+                    let splay = if req.skip_splay { 0 } else { self.splay };
+
+                    (req, splay, Trigger::Api)
+                }
+                (None, Some(t)) => {
+                    next_run = self.schedule.next_after(started);
+
+                    let splay = self.splay_within(t, next_run);
+
+                    (RunRequest::new(), splay, Trigger::Schedule)
+                }
+                // Woken up before the deadline without a request, go back to sleep.
+                (None, None) => continue,
+            };
+            APP_STATE.update_next_run(next_run.map(|t| t.to_rfc3339()));
+            RUN_QUEUE.set_current(Some(req.run_id.clone()));
+            std::thread::spawn(update_health_checks);
+
+            eprintln!("starting run {}", req.run_id);
+            let opts = RunOptions {
+                run_id: Some(req.run_id.clone()),
+                trigger,
+                ..RunOptions::new(req.apply(&self.args), splay)
+            };
+            match converge(opts) {
+                Finished::PostRun(done) => {
+                    eprintln!("chef-client exited with {}", done.exit_status())
+                }
+                Finished::Cancelled(_) => eprintln!("run {} was cancelled", req.run_id),
+                Finished::Failed(failed) => {
+                    eprintln!("run {} failed: {}", req.run_id, failed.error())
+                }
+            }
+
+            RUN_QUEUE.set_current(None);
+            APP_STATE.update_process_state("idle".into());
+            APP_STATE.update_splay_countdown(0);
+
+            // Slots that went by while converging are skipped.
+            let overran = match next_run {
+                Some(t) => t < Local::now(),
+                None => false,
+            };
+            if let Schedule::Cron(_) = self.schedule {
+                if overran {
+                    next_run = self.schedule.next_after(Local::now());
+                }
+            }
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn coalesces_only_requests_with_the_same_options() {
+        use super::{RunQueue, RunRequest, Submitted};
+
+        let queue = RunQueue::new();
+        queue.open();
+        queue.set_current(Some("20190115T103000-5f2a9c1e".into()));
+
+        let first = RunRequest::new();
+        let run_id = first.run_id.clone();
+        assert_eq!(
+            queue.submit(first, true),
+            Submitted::Accepted(run_id.clone())
+        );
+        assert_eq!(
+            queue.submit(RunRequest::new(), true),
+            Submitted::Accepted(run_id.clone())
+        );
+
+        let why_run = RunRequest {
+            why_run: true,
+            ..RunRequest::new()
+        };
+        assert_eq!(queue.submit(why_run, true), Submitted::Conflicting(run_id));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{broadcast::Broadcast, state::APP_STATE};
+use bytes::Bytes;
+use chrono::prelude::Local;
+use serde_json::Value;
+
+lazy_static! {
+    // Every change to the state of chefctl as a Server-Sent Events message.
+    pub static ref EVENTS: Broadcast = {
+        let b = Broadcast::new();
+        b.open();
+
+        b
+    };
+}
+
+// Formats `data` as a Server-Sent Events message of type `kind`. Every event
+// carries the time it was emitted at and the id of the run in progress.
+pub fn format(kind: &str, data: Value) -> Bytes {
+    let mut data = match data {
+        Value::Object(m) => m,
+        v => {
+            let mut m = serde_json::Map::new();
+            m.insert("value".into(), v);
+
+            m
+        }
+    };
+    data.insert("timestamp".into(), Local::now().to_rfc3339().into());
+    data.insert("run_id".into(), json!(APP_STATE.run_id()));
+
+    format!("event: {}\ndata: {}\n\n", kind, Value::Object(data)).into()
+}
+
+pub fn emit(kind: &str, data: Value) {
+    EVENTS.publish(format(kind, data), |_| ());
+}
+
+// Splits a message made by `format` back into its type and data.
+pub fn parse(msg: &[u8]) -> Option<(String, Value)> {
+    let msg = std::str::from_utf8(msg).ok()?;
+    let mut lines = msg.lines();
+    let kind = lines.next().filter(|l| l.starts_with("event: "))?;
+    let data = lines.next().filter(|l| l.starts_with("data: "))?;
+    let data = serde_json::from_str(&data["data: ".len()..]).ok()?;
+
+    Some((kind["event: ".len()..].to_string(), data))
+}
+
+mod test {
+    #[test]
+    fn formats_server_sent_events() {
+        use super::{format, parse};
+
+        let msg = format("process_state", json!({ "process_state": "running" }));
+        let msg = std::str::from_utf8(&msg).unwrap();
+
+        assert!(msg.starts_with("event: process_state\ndata: {"));
+        assert!(msg.contains("\"process_state\":\"running\""));
+        assert!(msg.contains("\"timestamp\":"));
+        assert!(msg.ends_with("}\n\n"));
+
+        let (kind, data) = parse(msg.as_bytes()).unwrap();
+        assert_eq!(kind, "process_state");
+        assert_eq!(data["process_state"], "running");
+    }
+}
@@ -48,16 +48,35 @@ where
     T: Serialize + Eq + std::hash::Hash,
 {
     checks: RwLock<HashMap<T, T>>,
+    passed: RwLock<HashMap<T, bool>>,
 }
 
 impl<T> State<T>
 where
     T: Serialize + Eq + std::hash::Hash,
 {
-    pub fn update_checks(&self, val: HashMap<T, T>) {
+    pub fn update_checks(&self, val: HashMap<T, T>, passed: HashMap<T, bool>) {
         let mut current_val = self.checks.write().unwrap();
+        let mut current_passed = self.passed.write().unwrap();
 
+        if let Ok(checks) = serde_json::to_value(&val) {
+            crate::events::emit("health", json!({ "checks": checks, "passed": passed }));
+        }
         *current_val = val;
+        *current_passed = passed;
+    }
+}
+
+impl<T> State<T>
+where
+    T: Serialize + Eq + std::hash::Hash + Clone,
+{
+    pub fn checks(&self) -> HashMap<T, T> {
+        self.checks.read().unwrap().clone()
+    }
+
+    pub fn passed(&self) -> HashMap<T, bool> {
+        self.passed.read().unwrap().clone()
     }
 }
 
@@ -68,6 +87,7 @@ lazy_static! {
     pub static ref HEALTH_STATE: State<String> = {
         State {
             checks: RwLock::new(HashMap::new()),
+            passed: RwLock::new(HashMap::new()),
         }
     };
 }
@@ -91,7 +111,9 @@ struct ChefClientCheck;
 impl HealthCheck for ChefClientCheck {
     fn run() -> CheckResult<(String, String)> {
         let mut chef_version = std::process::Command::new(crate::platform::CHEF_PATH);
-        chef_version.arg("--version");
+        chef_version
+            .arg("--version")
+            .stdout(std::process::Stdio::null());
         let status = chef_version.status()?;
         let result = if status.success() { "true" } else { "false" };
 
@@ -102,21 +124,44 @@ impl HealthCheck for ChefClientCheck {
     }
 }
 
-pub fn update_health_checks() -> CheckResult<()> {
+// A check passes when it ran and did not report `false`.
+fn passing(result: &CheckResult<(String, String)>) -> bool {
+    match result {
+        Ok((_, v)) => v != "false",
+        Err(_) => false,
+    }
+}
+
+// Runs every check and returns what each found and whether it passed, keyed
+// by the name of the check.
+pub fn run_checks() -> (HashMap<String, String>, HashMap<String, bool>) {
     let mut results: HashMap<String, String> = HashMap::new();
-    let result = match VersionCheck::run() {
+    let mut passed: HashMap<String, bool> = HashMap::new();
+    let result = VersionCheck::run();
+    let ok = passing(&result);
+    let result = match result {
         Ok(v) => v,
         Err(e) => ("VersionCheck".to_string(), e.description().to_owned()),
     };
+    passed.insert(result.0.clone(), ok);
     results.insert(result.0, result.1);
 
-    let result = match ChefClientCheck::run() {
+    let result = ChefClientCheck::run();
+    let ok = passing(&result);
+    let result = match result {
         Ok(v) => v,
         Err(e) => ("ChefClientCheck".to_string(), e.description().to_owned()),
     };
+    passed.insert(result.0.clone(), ok);
     results.insert(result.0, result.1);
 
-    HEALTH_STATE.update_checks(results);
+    (results, passed)
+}
+
+pub fn update_health_checks() -> CheckResult<()> {
+    let (results, passed) = run_checks();
+    HEALTH_STATE.update_checks(results, passed);
+    crate::metrics::health_updated();
 
     Ok(())
 }
@@ -0,0 +1,411 @@
+use crate::{
+    config::CONFIG,
+    process::{Outcome, Trigger},
+    state::RunSummary,
+};
+use chrono::prelude::{DateTime, Local, NaiveDate, TimeZone};
+use regex::Regex;
+use std::{
+    fmt::Write as FmtWrite,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+lazy_static! {
+    // Appending and trimming the history file must not interleave, see
+    // `History::lock` for other processes.
+    static ref HISTORY_LOCK: Mutex<()> = Mutex::new(());
+    static ref RESOURCES_UPDATED: Regex =
+        Regex::new(r"(\d+)/(\d+) resources updated").unwrap();
+}
+
+#[derive(Debug)]
+pub struct HistoryError(String);
+
+impl std::fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "history error: {}", self.0)
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+// Everything kept about a single run once it has finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub trigger: Trigger,
+    #[serde(flatten)]
+    pub summary: RunSummary,
+    // Seconds waited before `chef-client` was launched.
+    pub splay: u64,
+    pub args: String,
+    // Set instead of `exit_code` when `chef-client` was killed by a signal.
+    pub signal: Option<i32>,
+    pub log_path: String,
+    // Taken from the "X/Y resources updated" line `chef-client` ends with.
+    pub resources_updated: Option<u64>,
+    pub resources_total: Option<u64>,
+}
+
+// Finds the resource counts in the output of a run. The last line reporting
+// them wins, earlier ones may come from nested runs, e.g. of `chef-solo`.
+pub fn resource_counts<R: BufRead>(output: R) -> Option<(u64, u64)> {
+    let mut counts = None;
+
+    for line in output.split(b'\n').filter_map(Result::ok) {
+        let line = String::from_utf8_lossy(&line);
+        if let Some(c) = RESOURCES_UPDATED.captures(&line) {
+            if let (Ok(updated), Ok(total)) = (c[1].parse(), c[2].parse()) {
+                counts = Some((updated, total));
+            }
+        }
+    }
+
+    counts
+}
+
+// Takes either a date, meaning its local midnight, or an RFC 3339 timestamp
+// and returns it as seconds since the epoch.
+pub fn parse_since(since: &str) -> Result<i64, HistoryError> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(since) {
+        return Ok(t.timestamp());
+    }
+
+    NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| Local.from_local_datetime(&d.and_hms(0, 0, 0)).earliest())
+        .map(|t| t.timestamp())
+        .ok_or_else(|| {
+            HistoryError(format!(
+                "\"{}\" is neither a date like 2019-01-15 nor an RFC 3339 timestamp",
+                since
+            ))
+        })
+}
+
+// Which runs to list.
+#[derive(Debug, Default)]
+pub struct Filter {
+    // Only runs chef-client failed, not cancelled ones.
+    pub failed: bool,
+    // Only runs started at or after this many seconds since the epoch.
+    pub since: Option<i64>,
+    // Only the most recent runs left by the other criteria.
+    pub last: Option<usize>,
+}
+
+impl Filter {
+    // Returns the matching runs, most recent first.
+    pub fn apply(&self, records: Vec<RunRecord>) -> Vec<RunRecord> {
+        records
+            .into_iter()
+            .rev()
+            .filter(|r| !self.failed || r.summary.outcome == Outcome::Failure)
+            .filter(|r| match self.since {
+                Some(s) => r.summary.started_at >= s,
+                None => true,
+            })
+            .take(self.last.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+pub fn local_time(timestamp: i64) -> String {
+    Local
+        .timestamp(timestamp, 0)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn exit_text(r: &RunRecord) -> String {
+    match (r.summary.exit_code, r.signal) {
+        (Some(code), _) => code.to_string(),
+        (None, Some(signal)) => format!("signal {}", signal),
+        (None, None) => "-".into(),
+    }
+}
+
+fn resources_text(r: &RunRecord) -> String {
+    match (r.resources_updated, r.resources_total) {
+        (Some(updated), Some(total)) => format!("{}/{}", updated, total),
+        _ => "-".into(),
+    }
+}
+
+// Renders one run per line for people to read.
+pub fn table(records: &[RunRecord]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<26} {:<9} {:<19} {:>9} {:<9} {:>6} {:>9}",
+        "RUN ID", "TRIGGER", "STARTED", "DURATION", "OUTCOME", "EXIT", "RESOURCES"
+    );
+
+    for r in records {
+        let _ = writeln!(
+            out,
+            "{:<26} {:<9} {:<19} {:>8.1}s {:<9} {:>6} {:>9}",
+            r.summary.run_id,
+            r.trigger.as_str(),
+            local_time(r.summary.started_at),
+            r.summary.duration,
+            r.summary.outcome.as_str(),
+            exit_text(r),
+            resources_text(r),
+        );
+    }
+
+    out
+}
+
+// Renders everything known about a single run for people to read.
+pub fn details(r: &RunRecord) -> String {
+    let fields = [
+        ("run id", r.summary.run_id.clone()),
+        ("trigger", r.trigger.as_str().into()),
+        ("outcome", r.summary.outcome.as_str().into()),
+        ("exit", exit_text(r)),
+        ("started", local_time(r.summary.started_at)),
+        ("finished", local_time(r.summary.finished_at)),
+        ("duration", format!("{:.1}s", r.summary.duration)),
+        ("splay", format!("{}s", r.splay)),
+        ("resources", resources_text(r)),
+        (
+            "log",
+            crate::compress::locate(Path::new(&r.log_path))
+                .display()
+                .to_string(),
+        ),
+        ("command", r.args.clone()),
+    ];
+
+    let mut out = String::new();
+    for (name, value) in fields.iter() {
+        let _ = writeln!(out, "{:<10} {}", format!("{}:", name), value);
+    }
+
+    out
+}
+
+// Past runs as JSON lines, oldest first. Only the last `max_entries` runs are
+// kept.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    max_entries: usize,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(path: P, max_entries: usize) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_entries,
+        }
+    }
+
+    pub fn from_config() -> Self {
+        let config = &CONFIG.read().unwrap().history;
+
+        Self::new(&config.path, config.max_entries)
+    }
+
+    pub fn append(&self, record: &RunRecord) -> std::io::Result<()> {
+        let _lock = HISTORY_LOCK.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let _file_lock = self.lock()?;
+
+        let line = serde_json::to_string(record)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        drop(file);
+
+        let records = self.read()?;
+        if records.len() > self.max_entries {
+            self.rewrite(&records[records.len() - self.max_entries..])?;
+        }
+
+        Ok(())
+    }
+
+    // Keeps a one-shot chefctl and the daemon from appending at the same
+    // time. The history file itself is replaced when it is trimmed, so the
+    // lock is taken on a file next to it.
+    fn lock(&self) -> std::io::Result<File> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        crate::platform::lock_exclusive(&file)?;
+
+        Ok(file)
+    }
+
+    // Every run on record, oldest first. Lines that cannot be parsed, e.g.
+    // one cut short by a full disk, are skipped.
+    pub fn records(&self) -> std::io::Result<Vec<RunRecord>> {
+        let _lock = HISTORY_LOCK.lock().unwrap();
+
+        self.read()
+    }
+
+    pub fn find(&self, run_id: &str) -> std::io::Result<Option<RunRecord>> {
+        Ok(self
+            .records()?
+            .into_iter()
+            .find(|r| r.summary.run_id == run_id))
+    }
+
+    fn read(&self) -> std::io::Result<Vec<RunRecord>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            if let Ok(r) = serde_json::from_str(&line?) {
+                records.push(r);
+            }
+        }
+
+        Ok(records)
+    }
+
+    // Replaces the file with `records` without ever leaving it half written.
+    fn rewrite(&self, records: &[RunRecord]) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+
+        let mut file = File::create(&tmp)?;
+        // The trimmed history stays as readable as appending left it.
+        file.set_permissions(std::fs::metadata(&self.path)?.permissions())?;
+        for r in records {
+            writeln!(file, "{}", serde_json::to_string(r)?)?;
+        }
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+mod test {
+    #[test]
+    fn finds_resource_counts() {
+        use super::resource_counts;
+
+        let output = "Starting Chef Client\n\
+                      Chef Client finished, 3/120 resources updated in 02 seconds\n";
+
+        assert_eq!(resource_counts(output.as_bytes()), Some((3, 120)));
+        assert_eq!(resource_counts("ERROR: boom\n".as_bytes()), None);
+    }
+
+    #[cfg(test)]
+    fn record(run_id: &str, outcome: crate::process::Outcome, started_at: i64) -> super::RunRecord {
+        super::RunRecord {
+            trigger: crate::process::Trigger::Schedule,
+            summary: crate::state::RunSummary {
+                run_id: run_id.into(),
+                outcome,
+                exit_code: Some(0),
+                started_at,
+                finished_at: started_at,
+                duration: 0.0,
+            },
+            splay: 0,
+            args: String::new(),
+            signal: None,
+            log_path: String::new(),
+            resources_updated: Some(3),
+            resources_total: Some(120),
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_last_entries() {
+        use super::History;
+        use crate::process::Outcome;
+
+        let dir = std::env::temp_dir().join(format!("chefctl-history-{}", std::process::id()));
+        let history = History::new(dir.join("history.jsonl"), 2);
+        for i in 0..3 {
+            history
+                .append(&record(&format!("run-{}", i), Outcome::Success, i))
+                .unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                if i == 0 {
+                    let mode = std::fs::Permissions::from_mode(0o604);
+                    std::fs::set_permissions(dir.join("history.jsonl"), mode).unwrap();
+                }
+            }
+        }
+        let ids: Vec<String> = history
+            .records()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.summary.run_id)
+            .collect();
+        let meta = std::fs::metadata(dir.join("history.jsonl")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(ids, vec!["run-1", "run-2"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(meta.permissions().mode() & 0o777, 0o604);
+        }
+    }
+
+    #[test]
+    fn filters_most_recent_first() {
+        use super::{parse_since, Filter};
+        use crate::process::Outcome;
+
+        let records = vec![
+            record("a", Outcome::Failure, 100),
+            record("b", Outcome::Success, 200),
+            record("c", Outcome::Failure, 300),
+            record("d", Outcome::Cancelled, 400),
+        ];
+        let ids = |f: Filter| -> Vec<String> {
+            f.apply(records.clone())
+                .into_iter()
+                .map(|r| r.summary.run_id)
+                .collect()
+        };
+
+        assert_eq!(ids(Filter::default()), vec!["d", "c", "b", "a"]);
+        assert_eq!(
+            ids(Filter {
+                failed: true,
+                ..Default::default()
+            }),
+            vec!["c", "a"]
+        );
+        assert_eq!(
+            ids(Filter {
+                since: Some(200),
+                last: Some(2),
+                ..Default::default()
+            }),
+            vec!["d", "c"]
+        );
+        assert_eq!(parse_since("2019-01-15T10:00:00Z").unwrap(), 1547546400);
+        assert!(parse_since("2019-01-15").is_ok());
+        assert!(parse_since("yesterday").is_err());
+    }
+}
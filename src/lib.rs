@@ -1,20 +1,57 @@
 #![allow(dead_code)]
 
+extern crate actix;
+#[cfg(not(target_os = "windows"))]
+extern crate actix_net;
 extern crate actix_web;
+extern crate bytes;
+extern crate flate2;
+extern crate futures;
+extern crate libc;
+#[cfg(not(target_os = "windows"))]
+extern crate openssl;
 extern crate rand;
+extern crate regex;
 extern crate serde;
-extern crate serde_json;
+extern crate serde_yaml;
+#[cfg(not(target_os = "windows"))]
+extern crate tokio_io;
+#[cfg(not(target_os = "windows"))]
+extern crate tokio_tcp;
+#[cfg(not(target_os = "windows"))]
+extern crate tokio_timer;
+#[cfg(not(target_os = "windows"))]
+extern crate tokio_uds;
 
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
 pub mod api;
+pub mod auth;
+pub mod broadcast;
+pub mod client;
+pub mod compress;
+pub mod config;
+pub mod daemon;
+pub mod events;
 pub mod health;
+pub mod history;
+pub mod metrics;
 pub mod platform;
 pub mod process;
+pub mod retention;
+pub mod schedule;
 pub mod state;
+pub mod statsd;
+pub mod status;
 pub mod symlink;
+pub mod tail;
+#[cfg(not(target_os = "windows"))]
+pub mod tls;
+pub mod wait;
 
 pub const VERSION: &str = "0.0.1";
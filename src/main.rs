@@ -4,14 +4,23 @@ extern crate clap;
 #[macro_use]
 extern crate lazy_static;
 extern crate ctrlc;
+extern crate serde_json;
 
 use chefctl::{
-    api::start_api_server,
+    api::{remove_socket, start_api_server, API_PREFIX},
+    client::Client,
+    config::CONFIG,
+    daemon::Daemon,
+    history::{self, Filter, History},
     platform::{CONFIG_FILE_PATH, FD_NULL, LOCK_FILE_PATH},
-    process::{ChefClientArgs, PostRun, PreRun, Running, Waiting},
+    process::{converge, ChefClientArgs, Finished, RunOptions},
+    schedule::Schedule,
+    status::Report,
+    tail::{Log, Tail},
+    wait::{Waited, Waiter},
     VERSION,
 };
-use clap::Arg;
+use clap::{Arg, SubCommand};
 use std::{
     collections::HashMap,
     sync::atomic::{AtomicBool, Ordering},
@@ -47,10 +56,21 @@ fn handle_signals() {
         std::thread::sleep(std::time::Duration::from_millis(500));
     }
     println!("SIGINT received");
+
+    // Let the state machine stop chef-client before going away.
+    if chefctl::process::cancel() {
+        while chefctl::process::in_progress() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+    remove_socket();
     std::process::exit(1);
 }
 
-fn args_from_clap(matches: clap::ArgMatches) -> String {
+// Splay used for a single run started from the command line.
+const ONE_SHOT_SPLAY: u32 = 10;
+
+fn args_from_clap(matches: &clap::ArgMatches) -> String {
     let mut opts = ChefClientArgs::new();
 
     for (k, v) in &(*APP_TO_CHEF) {
@@ -73,91 +93,460 @@ fn args_from_clap(matches: clap::ArgMatches) -> String {
 }
 
 fn main() -> Result<(), std::io::Error> {
-    // Start REST API server.
-    std::thread::spawn(|| {
-        start_api_server("127.0.0.1:6666")
-            .map_err(|e| {
-                eprintln!("api server disabled because {}", e);
+    // This is currently the best Rust has to offer for signal handling.
+    std::thread::spawn(handle_signals);
+
+    let matches = clap::App::new("chefctl")
+        .about("a rust wrapper around chef-client")
+        .version(VERSION)
+        .arg(
+            Arg::with_name("config")
+                .short("C")
+                .help("config file")
+                .default_value(CONFIG_FILE_PATH),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .help("verbose output from chefctl"),
+        )
+        .arg(Arg::with_name("color").short("c").help("enable colors"))
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .help("enable chef debugging"),
+        )
+        .arg(
+            Arg::with_name("human")
+                .short("H")
+                .help("human readable output"),
+        )
+        .arg(
+            Arg::with_name("why-run")
+                .short("n")
+                .help("enable why-run mode"),
+        )
+        .arg(
+            Arg::with_name("immediate")
+                .short("i")
+                .help("execute immediately. no splay. safely stop other chefctl processes.")
+                .conflicts_with("splay"),
+        )
+        .arg(
+            Arg::with_name("splay")
+                .short("s")
+                .help("maximum number of seconds for a random splay.")
+                .default_value("870"),
+        )
+        .arg(
+            Arg::with_name("lock-timeout")
+                .short("l")
+                .help("lock timeout in seconds")
+                .default_value("1800"),
+        )
+        .arg(
+            Arg::with_name("lock-file")
+                .short("L")
+                .help("lock file location")
+                .default_value(LOCK_FILE_PATH),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .help("do not print output to terminal"),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("keep converging on an interval")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .help("seconds between runs, overrides the config file"),
+                )
+                .arg(
+                    Arg::with_name("splay")
+                        .long("splay")
+                        .takes_value(true)
+                        .help("maximum number of seconds for a random splay before each run"),
+                )
+                .arg(
+                    Arg::with_name("schedule")
+                        .long("schedule")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .conflicts_with("interval")
+                        .help("cron-style expression to run on, may be given more than once"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("list past runs, most recent first")
+                .arg(
+                    Arg::with_name("run-id")
+                        .help("show everything recorded about this run instead"),
+                )
+                .arg(
+                    Arg::with_name("failed")
+                        .long("failed")
+                        .help("only runs chef-client failed"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .help("only runs started since a date (2019-01-15) or RFC 3339 timestamp"),
+                )
+                .arg(
+                    Arg::with_name("last")
+                        .long("last")
+                        .takes_value(true)
+                        .help("only the last N matching runs"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print JSON instead of a table"),
+                )
+                .arg(
+                    Arg::with_name("log")
+                        .long("log")
+                        .requires("run-id")
+                        .conflicts_with("json")
+                        .help("print the log of the run, even once it is compressed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("follow the output of the current run, exits with its exit code")
+                .arg(
+                    Arg::with_name("lines")
+                        .short("n")
+                        .long("lines")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("number of lines already written to show first"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("wait for the run in progress to finish, exits with its exit code")
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .help("seconds to wait at most before exiting with 124"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("attach")
+                .about("stream the output of the run in progress until it finishes")
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .help("seconds to follow at most before exiting with 124"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("show what the local daemon is doing, exits 1 when unhealthy")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print JSON instead of text"),
+                ),
+        )
+        .get_matches();
+
+    if let Some(path) = matches.value_of("config") {
+        if let Err(e) = chefctl::config::load(path) {
+            eprintln!("could not load \"{}\": {}", path, e);
+
+            std::process::exit(1);
+        }
+    }
+
+    // Subcommands that only look at a daemon must not start another API server.
+    if let Some(history) = matches.subcommand_matches("history") {
+        show_history(history, matches.is_present("color"));
+
+        return Ok(());
+    }
+    if let Some(tail) = matches.subcommand_matches("tail") {
+        let lines = number_or(tail.value_of("lines"), 10);
+        let link = CONFIG.read().unwrap().output.current_path();
+        let tail = Tail::new(
+            &link,
+            History::from_config(),
+            matches.is_present("color"),
+            lines,
+        );
+        let stdout = std::io::stdout();
+        match tail.follow(&mut stdout.lock()) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("could not follow {}: {}", link.display(), e);
 
                 std::process::exit(1);
-            })
-            .expect("api creation failed, exiting");
-    });
+            }
+        }
+    }
+    for (name, stream) in &[("wait", false), ("attach", true)] {
+        if let Some(sub) = matches.subcommand_matches(name) {
+            let lock_file = matches.value_of("lock-file").unwrap_or(LOCK_FILE_PATH);
+            let timeout = sub
+                .value_of("timeout")
+                .map(|t| std::time::Duration::from_secs(number_or(Some(t), 0)));
+            let code = wait_for_run(lock_file, timeout, *stream, matches.is_present("color"));
 
-    // This is currently the best Rust has to offer for signal handling.
-    std::thread::spawn(handle_signals);
+            std::process::exit(code);
+        }
+    }
+    if let Some(status) = matches.subcommand_matches("status") {
+        let lock_file = matches.value_of("lock-file").unwrap_or(LOCK_FILE_PATH);
+        show_status(status, lock_file);
+
+        return Ok(());
+    }
+
+    // Start REST API server.
+    let api = CONFIG.read().unwrap().api.clone();
+    if let Err(e) = api.listen() {
+        eprintln!("{}", e);
+
+        std::process::exit(1);
+    }
+    // A one-shot run leaves the API to a daemon that is already serving it.
+    let daemon_address = if matches.subcommand_matches("daemon").is_none() {
+        Client::from_config(&api)
+            .ok()
+            .filter(|c| c.get(&format!("{}/status", API_PREFIX)).is_ok())
+            .map(|c| c.address())
+    } else {
+        None
+    };
+    match daemon_address {
+        Some(address) => println!("chefctl is serving the api at {} already", address),
+        None => {
+            std::thread::spawn(move || {
+                if let Err(e) = start_api_server(&api) {
+                    eprintln!("api server disabled because {}", e);
+                }
+            });
+        }
+    }
+
+    let args = args_from_clap(&matches);
+
+    if let Some(daemon) = matches.subcommand_matches("daemon") {
+        let (schedule, splay) = {
+            let config = &CONFIG.read().unwrap().daemon;
+            let exprs: Vec<String> = match daemon.values_of("schedule") {
+                Some(v) => v.map(String::from).collect(),
+                None if daemon.is_present("interval") => Vec::new(),
+                None => config.schedule.clone(),
+            };
+            let schedule = if exprs.is_empty() {
+                let interval = number_or(daemon.value_of("interval"), config.interval);
+
+                Schedule::Interval(std::time::Duration::from_secs(interval))
+            } else {
+                Schedule::cron(&exprs).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+
+                    std::process::exit(1);
+                })
+            };
+
+            (schedule, number_or(daemon.value_of("splay"), config.splay))
+        };
 
-    let args = args_from_clap(
-        clap::App::new("chefctl")
-            .about("a rust wrapper around chef-client")
-            .version(VERSION)
-            .arg(
-                Arg::with_name("config")
-                    .short("C")
-                    .help("config file")
-                    .default_value(CONFIG_FILE_PATH),
-            )
-            .arg(
-                Arg::with_name("verbose")
-                    .short("v")
-                    .help("verbose output from chefctl"),
-            )
-            .arg(Arg::with_name("color").short("c").help("enable colors"))
-            .arg(
-                Arg::with_name("debug")
-                    .short("d")
-                    .help("enable chef debugging"),
-            )
-            .arg(
-                Arg::with_name("human")
-                    .short("H")
-                    .help("human readable output"),
-            )
-            .arg(
-                Arg::with_name("why-run")
-                    .short("n")
-                    .help("enable why-run mode"),
-            )
-            .arg(
-                Arg::with_name("immediate")
-                    .short("i")
-                    .help("execute immediately. no splay. safely stop other chefctl processes.")
-                    .conflicts_with("splay"),
-            )
-            .arg(
-                Arg::with_name("splay")
-                    .short("s")
-                    .help("maximum number of seconds for a random splay.")
-                    .default_value("870"),
-            )
-            .arg(
-                Arg::with_name("lock-timeout")
-                    .short("l")
-                    .help("lock timeout in seconds")
-                    .default_value("1800"),
-            )
-            .arg(
-                Arg::with_name("lock-file")
-                    .short("L")
-                    .help("lock file location")
-                    .default_value(LOCK_FILE_PATH),
-            )
-            .arg(
-                Arg::with_name("quiet")
-                    .short("q")
-                    .help("do not print output to terminal"),
-            )
-            .get_matches(),
-    );
+        Daemon::new(args, schedule, splay).run();
+
+        return Ok(());
+    }
 
     // Should be moved to a worker thread but ok here for now.
     std::thread::spawn(chefctl::health::update_health_checks);
 
     // Run the state machine.
-    let pre_run = chefctl::process::StateMachine::<PreRun>::new(args);
-    let waiting = chefctl::process::StateMachine::<Waiting>::from(pre_run);
-    let running = chefctl::process::StateMachine::<Running>::from(waiting);
-    let ___done = chefctl::process::StateMachine::<PostRun>::from(running);
+    if let Finished::Failed(failed) = converge(RunOptions::new(args, ONE_SHOT_SPLAY)) {
+        eprintln!("{}", failed.error());
+    }
+    chefctl::compress::wait();
+    remove_socket();
 
     Ok(())
 }
+
+fn show_history(matches: &clap::ArgMatches, color: bool) {
+    let history = History::from_config();
+    let json = matches.is_present("json");
+
+    if let Some(run_id) = matches.value_of("run-id") {
+        match history.find(run_id) {
+            Ok(Some(r)) if matches.is_present("log") => print_log(&r.log_path, color),
+            Ok(Some(r)) if json => println!("{}", to_json(&r)),
+            Ok(Some(r)) => print!("{}", history::details(&r)),
+            Ok(None) => {
+                eprintln!("no run {} in the history", run_id);
+
+                std::process::exit(1);
+            }
+            Err(e) => exit_unreadable(e),
+        }
+
+        return;
+    }
+
+    let filter = Filter {
+        failed: matches.is_present("failed"),
+        since: matches.value_of("since").map(|s| {
+            history::parse_since(s).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+
+                std::process::exit(1);
+            })
+        }),
+        last: matches.value_of("last").map(|n| number_or(Some(n), 0)),
+    };
+    let records = match history.records() {
+        Ok(r) => filter.apply(r),
+        Err(e) => exit_unreadable(e),
+    };
+
+    if json {
+        println!("{}", to_json(&records));
+    } else {
+        print!("{}", history::table(&records));
+    }
+}
+
+// Prints a whole log, compressed or not.
+fn print_log(path: &str, color: bool) {
+    let stdout = std::io::stdout();
+    let printed = Log::open(path, color).and_then(|mut log| log.finish(&mut stdout.lock()));
+
+    if let Err(e) = printed {
+        eprintln!("could not read {}: {}", path, e);
+
+        std::process::exit(1);
+    }
+}
+
+// Asks the daemon and falls back to the files it leaves behind when it does
+// not answer.
+fn show_status(matches: &clap::ArgMatches, lock_file: &str) {
+    let report = Client::from_config(&CONFIG.read().unwrap().api)
+        .and_then(|c| {
+            Report::from_api(&c, lock_file).map_err(|e| {
+                eprintln!(
+                    "could not query chefctl at {} ({}), reading local files",
+                    c.address(),
+                    e
+                );
+
+                e
+            })
+        })
+        .unwrap_or_else(|_| Report::from_files(lock_file));
+
+    if matches.is_present("json") {
+        println!("{}", to_json(&report));
+    } else {
+        print!("{}", report.render());
+    }
+
+    if !report.healthy {
+        std::process::exit(1);
+    }
+}
+
+// Blocks until the run another chefctl is doing has finished and returns the
+// code to exit with. With `stream` its output is printed from the start.
+fn wait_for_run(
+    lock_file: &str,
+    timeout: Option<std::time::Duration>,
+    stream: bool,
+    color: bool,
+) -> i32 {
+    let waiter = {
+        let config = CONFIG.read().unwrap();
+
+        Waiter::new(
+            config.output.current_path(),
+            lock_file,
+            History::from_config(),
+            Client::from_config(&config.api).ok(),
+        )
+    };
+    let log_path = match waiter.current_run() {
+        Some(p) => p,
+        None => {
+            eprintln!("no run in progress");
+
+            return 0;
+        }
+    };
+
+    let mut log = if stream {
+        match Log::open(&log_path, color) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("could not open {}: {}", log_path.display(), e);
+
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let waited = waiter.wait(&log_path, timeout, || match log {
+        Some(ref mut l) => l.pump(&mut out),
+        None => Ok(()),
+    });
+    if let Some(ref mut l) = log {
+        let _ = l.finish(&mut out);
+    }
+
+    match waited {
+        Ok(Waited::TimedOut) => eprintln!("the run is still in progress"),
+        Ok(Waited::Lost) => eprintln!(
+            "the run writing {} ended without being recorded in the history",
+            log_path.display()
+        ),
+        Ok(Waited::Finished(_)) => {}
+        Err(e) => {
+            eprintln!("could not wait for the run: {}", e);
+
+            return 1;
+        }
+    }
+
+    waited.map(|w| w.exit_code()).unwrap_or(1)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).expect("history records serialize")
+}
+
+fn exit_unreadable(e: std::io::Error) -> ! {
+    eprintln!("could not read the history: {}", e);
+
+    std::process::exit(1);
+}
+
+fn number_or<T: std::str::FromStr>(value: Option<&str>, default: T) -> T {
+    match value {
+        Some(v) => v.parse().unwrap_or_else(|_| {
+            eprintln!("\"{}\" is not a valid number", v);
+
+            std::process::exit(1);
+        }),
+        None => default,
+    }
+}
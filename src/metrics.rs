@@ -0,0 +1,408 @@
+use crate::{
+    config::CONFIG,
+    health::HEALTH_STATE,
+    process::Outcome,
+    state::{RunSummary, APP_STATE},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as FmtWrite,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Name of the file written for node_exporter's textfile collector.
+pub const TEXTFILE_NAME: &str = "chefctl.prom";
+
+// Every value `State::update_process_state` is called with.
+const PROCESS_STATES: &[&str] = &[
+    "init",
+    "pre-run",
+    "waiting",
+    "running",
+    "post-run",
+    "cancelled",
+    "idle",
+];
+
+lazy_static! {
+    // Every writer in this process shares the temporary file, the API and the
+    // run thread must take turns.
+    static ref TEXTFILE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+const OUTCOMES: &[Outcome] = &[Outcome::Success, Outcome::Failure, Outcome::Cancelled];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+#[derive(Debug)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+// A metric and all of its samples, e.g. one per label value.
+#[derive(Debug)]
+pub struct Family {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+    pub samples: Vec<Sample>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: Kind, help: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn sample<V: Into<f64>>(mut self, value: V) -> Self {
+        self.samples.push(Sample {
+            labels: Vec::new(),
+            value: value.into(),
+        });
+
+        self
+    }
+
+    fn labelled<V: Into<f64>>(mut self, label: &'static str, label_value: &str, value: V) -> Self {
+        self.samples.push(Sample {
+            labels: vec![(label, label_value.to_string())],
+            value: value.into(),
+        });
+
+        self
+    }
+}
+
+fn flag(b: bool) -> u8 {
+    if b {
+        1
+    } else {
+        0
+    }
+}
+
+// A consistent view of everything chefctl reports as metrics, taken from the
+// global state in one go so that every exporter renders the same numbers.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub process_state: String,
+    pub splay: u64,
+    pub splay_countdown: u64,
+    pub last_run: Option<RunSummary>,
+    pub consecutive_failures: u64,
+    pub runs_total: BTreeMap<String, u64>,
+    pub health: BTreeMap<String, bool>,
+    pub resident_memory_bytes: Option<u64>,
+}
+
+impl Snapshot {
+    pub fn collect() -> Self {
+        Self {
+            process_state: APP_STATE.process_state(),
+            splay: APP_STATE.splay(),
+            splay_countdown: APP_STATE.splay_countdown(),
+            last_run: APP_STATE.last_run(),
+            consecutive_failures: APP_STATE.consecutive_failures(),
+            runs_total: APP_STATE.runs_total(),
+            health: HEALTH_STATE.passed().into_iter().collect(),
+            resident_memory_bytes: resident_memory_bytes(),
+        }
+    }
+
+    pub fn families(&self) -> Vec<Family> {
+        let mut state = Family::new(
+            "chefctl_process_state",
+            Kind::Gauge,
+            "1 for the state the state machine is in.",
+        );
+        for s in PROCESS_STATES {
+            state = state.labelled("state", s, flag(*s == self.process_state));
+        }
+
+        let mut runs = Family::new(
+            "chefctl_runs_total",
+            Kind::Counter,
+            "Runs since chefctl started by outcome.",
+        );
+        for o in OUTCOMES {
+            let label = o.as_str();
+            let v = self.runs_total.get(label).cloned().unwrap_or(0);
+            runs = runs.labelled("outcome", label, v as f64);
+        }
+
+        let mut families = vec![
+            state,
+            Family::new(
+                "chefctl_splay_seconds",
+                Kind::Gauge,
+                "Splay chosen for the current or last run.",
+            )
+            .sample(self.splay as f64),
+            Family::new(
+                "chefctl_splay_countdown_seconds",
+                Kind::Gauge,
+                "Seconds left until chef-client is launched.",
+            )
+            .sample(self.splay_countdown as f64),
+            runs,
+            Family::new(
+                "chefctl_consecutive_failures",
+                Kind::Gauge,
+                "Failed runs since the last successful one.",
+            )
+            .sample(self.consecutive_failures as f64),
+        ];
+
+        if let Some(ref run) = self.last_run {
+            let mut outcome = Family::new(
+                "chefctl_last_run_outcome",
+                Kind::Gauge,
+                "1 for the outcome of the last run.",
+            );
+            for o in OUTCOMES {
+                outcome = outcome.labelled("outcome", o.as_str(), flag(*o == run.outcome));
+            }
+            families.push(outcome);
+            // A single series, replaced by every run, so run ids do not pile
+            // up as series.
+            families.push(
+                Family::new(
+                    "chefctl_last_run_info",
+                    Kind::Gauge,
+                    "1, labelled with the id of the last run.",
+                )
+                .labelled("run_id", &run.run_id, 1),
+            );
+            families.push(
+                Family::new(
+                    "chefctl_last_run_timestamp_seconds",
+                    Kind::Gauge,
+                    "When the last run finished.",
+                )
+                .sample(run.finished_at as f64),
+            );
+            families.push(
+                Family::new(
+                    "chefctl_last_run_duration_seconds",
+                    Kind::Gauge,
+                    "How long chef-client ran for in the last run, splay excluded.",
+                )
+                .sample(run.duration),
+            );
+            if let Some(code) = run.exit_code {
+                families.push(
+                    Family::new(
+                        "chefctl_last_run_exit_code",
+                        Kind::Gauge,
+                        "Exit code of chef-client in the last run.",
+                    )
+                    .sample(code),
+                );
+            }
+        }
+
+        if !self.health.is_empty() {
+            let mut health = Family::new(
+                "chefctl_health_check",
+                Kind::Gauge,
+                "1 if the health check passed.",
+            );
+            for (check, passed) in &self.health {
+                health = health.labelled("check", check, flag(*passed));
+            }
+            families.push(health);
+        }
+
+        if let Some(rss) = self.resident_memory_bytes {
+            families.push(
+                Family::new(
+                    "chefctl_resident_memory_bytes",
+                    Kind::Gauge,
+                    "Resident memory of chefctl itself.",
+                )
+                .sample(rss as f64),
+            );
+        }
+
+        families
+    }
+
+    // Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        for f in self.families() {
+            let kind = match f.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", f.name, f.help);
+            let _ = writeln!(out, "# TYPE {} {}", f.name, kind);
+
+            for s in f.samples {
+                let labels: Vec<String> = s
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                    .collect();
+
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", f.name, s.value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", f.name, labels.join(","), s.value);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+// Writes `snapshot` to `dir` for node_exporter's textfile collector. The file
+// is written next to its final name and renamed over it, so the collector
+// never reads a partial file.
+pub fn write_textfile_to<P: AsRef<Path>>(dir: P, snapshot: &Snapshot) -> std::io::Result<()> {
+    let path = dir.as_ref().join(TEXTFILE_NAME);
+    let tmp: PathBuf = dir
+        .as_ref()
+        .join(format!(".{}.{}.tmp", TEXTFILE_NAME, std::process::id()));
+    let _lock = TEXTFILE_LOCK.lock().unwrap();
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(snapshot.to_prometheus().as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    if let Err(e) = std::fs::rename(&tmp, &path) {
+        let _ = std::fs::remove_file(&tmp);
+
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// Refreshes the textfile if `metrics.textfile_dir` is configured.
+pub fn write_textfile() {
+    let dir = match CONFIG.read().unwrap().metrics.textfile_dir {
+        Some(ref d) => d.clone(),
+        None => return,
+    };
+
+    if let Err(e) = write_textfile_to(&dir, &Snapshot::collect()) {
+        eprintln!("could not write metrics to \"{}\": {}", dir, e);
+    }
+}
+
+// Hands the outcome of a run to every configured exporter.
+pub fn run_finished(run: &RunSummary) {
+    write_textfile();
+
+    if let Some(statsd) = crate::statsd::from_config() {
+        if let Err(e) = statsd.send(&statsd.run_lines(run)) {
+            eprintln!("could not send metrics to statsd: {}", e);
+        }
+    }
+}
+
+// Hands fresh health check results to every configured exporter.
+pub fn health_updated() {
+    write_textfile();
+
+    if let Some(statsd) = crate::statsd::from_config() {
+        let passed = HEALTH_STATE.passed().into_iter().collect();
+        if let Err(e) = statsd.send(&statsd.health_lines(&passed)) {
+            eprintln!("could not send metrics to statsd: {}", e);
+        }
+    }
+}
+
+// Label values may not contain unescaped backslashes, quotes or newlines.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_bytes() -> Option<u64> {
+    None
+}
+
+mod test {
+    #[test]
+    fn renders_prometheus_text_format() {
+        use super::Snapshot;
+        use crate::{process::Outcome, state::RunSummary};
+
+        let mut snapshot = Snapshot {
+            process_state: "running".into(),
+            splay: 42,
+            consecutive_failures: 2,
+            last_run: Some(RunSummary {
+                run_id: "20190115T103000-5f2a9c1e".into(),
+                outcome: Outcome::Failure,
+                exit_code: Some(1),
+                started_at: 1547548200,
+                finished_at: 1547548260,
+                duration: 60.5,
+            }),
+            ..Default::default()
+        };
+        snapshot.health.insert("Chef \"Version\"".into(), true);
+        let text = snapshot.to_prometheus();
+
+        assert!(text.contains("chefctl_process_state{state=\"running\"} 1\n"));
+        assert!(text.contains("chefctl_process_state{state=\"idle\"} 0\n"));
+        assert!(text.contains("chefctl_splay_seconds 42\n"));
+        assert!(text.contains("chefctl_consecutive_failures 2\n"));
+        assert!(text.contains("chefctl_last_run_outcome{outcome=\"failure\"} 1\n"));
+        assert!(text.contains("chefctl_last_run_duration_seconds 60.5\n"));
+        assert!(text.contains("chefctl_last_run_timestamp_seconds 1547548260\n"));
+        assert!(text.contains("chefctl_last_run_info{run_id=\"20190115T103000-5f2a9c1e\"} 1\n"));
+        assert_eq!(text.matches("run_id=").count(), 1);
+        assert!(text.contains("chefctl_health_check{check=\"Chef \\\"Version\\\"\"} 1\n"));
+        assert!(text.contains("# TYPE chefctl_runs_total counter\n"));
+    }
+
+    #[test]
+    fn writes_textfile_atomically() {
+        use super::{write_textfile_to, Snapshot, TEXTFILE_NAME};
+
+        let dir = std::env::temp_dir().join(format!("chefctl-textfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = Snapshot {
+            process_state: "idle".into(),
+            ..Default::default()
+        };
+
+        write_textfile_to(&dir, &snapshot).unwrap();
+        let written = std::fs::read_to_string(dir.join(TEXTFILE_NAME)).unwrap();
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(written, snapshot.to_prometheus());
+        assert_eq!(entries, 1);
+    }
+}
@@ -8,11 +8,13 @@ pub const CONFIG_FILE_PATH: &str = "C:\\chef\\chefctl.yml";
 #[cfg(target_os = "windows")]
 pub const LOCK_FILE_PATH: &str = "C:\\chef\\chefctl.lock";
 #[cfg(target_os = "windows")]
-pub const CHEF_RUN_CURRENT_PATH: &str = "C:\\chef\\outputs\\chef.cur.out";
-#[cfg(target_os = "windows")]
-pub const CHEF_RUN_LAST_PATH: &str = "C:\\chef\\outputs\\chef.last.out";
+pub const OUTPUT_DIR: &str = "C:\\chef\\outputs";
 #[cfg(target_os = "windows")]
 pub const FD_NULL: &str = "NUL";
+#[cfg(target_os = "windows")]
+pub const AUDIT_LOG_PATH: &str = "C:\\chef\\chefctl.audit.log";
+#[cfg(target_os = "windows")]
+pub const HISTORY_PATH: &str = "C:\\chef\\chefctl.history.jsonl";
 
 // Non-Windows file paths.
 #[cfg(not(target_os = "windows"))]
@@ -24,10 +26,115 @@ pub const CONFIG_FILE_PATH: &str = "/etc/chefctl.yml";
 #[cfg(not(target_os = "windows"))]
 pub const LOCK_FILE_PATH: &str = "/var/lock/subsys/chefctl";
 #[cfg(not(target_os = "windows"))]
-pub const CHEF_RUN_CURRENT_PATH: &str = "/tmp/chef.cur.out";
-#[cfg(not(target_os = "windows"))]
-pub const CHEF_RUN_LAST_PATH: &str = "/tmp/chef.last.out";
+pub const OUTPUT_DIR: &str = "/var/chef/outputs";
 #[cfg(not(target_os = "windows"))]
 pub const FD_NULL: &str = "/dev/null";
-// #[cfg(not(target_os = "windows"))]
-// pub const CHEF_RUN_LAST_PATH: &str = "/var/chef/outputs/chef.last.out";
+#[cfg(not(target_os = "windows"))]
+pub const AUDIT_LOG_PATH: &str = "/var/log/chefctl.audit.log";
+#[cfg(not(target_os = "windows"))]
+pub const HISTORY_PATH: &str = "/var/lib/chefctl/history.jsonl";
+
+// The name of this host as reported by the operating system.
+#[cfg(not(target_os = "windows"))]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "localhost".into();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(target_os = "windows")]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".into())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn lookup_error(kind: &str, name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no such {}: {}", kind, name),
+    )
+}
+
+// Resolves a user name or numeric id to a uid.
+#[cfg(not(target_os = "windows"))]
+pub fn lookup_user(name: &str) -> std::io::Result<libc::uid_t> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|_| lookup_error("user", name))?;
+    let pw = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if pw.is_null() {
+        return Err(lookup_error("user", name));
+    }
+
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+// Resolves a group name or numeric id to a gid.
+#[cfg(not(target_os = "windows"))]
+pub fn lookup_group(name: &str) -> std::io::Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|_| lookup_error("group", name))?;
+    let gr = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if gr.is_null() {
+        return Err(lookup_error("group", name));
+    }
+
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+// Blocks until no other process holds a lock on `file`. The lock is released
+// when `file` is closed.
+#[cfg(not(target_os = "windows"))]
+pub fn lock_exclusive(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+            0 => return Ok(()),
+            _ => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn lock_exclusive(_: &std::fs::File) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Changes the owner and/or group of `path`, `None` leaves it as it is.
+#[cfg(not(target_os = "windows"))]
+pub fn chown<P: AsRef<std::path::Path>>(
+    path: P,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let ret = unsafe {
+        libc::chown(
+            c_path.as_ptr(),
+            uid.unwrap_or(!0 as libc::uid_t),
+            gid.unwrap_or(!0 as libc::gid_t),
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
@@ -1,109 +1,245 @@
 use crate::{
-    platform::{CHEF_PATH, CHEF_RUN_CURRENT_PATH, CHEF_RUN_LAST_PATH},
-    state::APP_STATE,
+    broadcast::Broadcast,
+    config::CONFIG,
+    events::emit,
+    history::{resource_counts, History, RunRecord},
+    platform::CHEF_PATH,
+    state::{RunSummary, APP_STATE},
     symlink::create_symlink,
 };
-use chrono::prelude::{DateTime, Datelike, Local, Timelike};
+use bytes::Bytes;
+use chrono::prelude::Local;
 use rand::{thread_rng, Rng};
 use std::{
     cell::RefCell,
     fs::{File, OpenOptions},
-    io::{stderr, stdout, BufRead, BufReader, Write},
-    path::PathBuf,
+    io::{stderr, stdout, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
     process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+    },
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-const BUFFER_CAPACITY: usize = 4096;
+const BUFFER_CAPACITY: usize = 64 * 1024;
 
-fn timestamp() -> String {
-    let now: DateTime<Local> = Local::now();
+// How long to wait for the output of `chef-client` to end once it exited.
+// Daemons it started may keep its pipes open.
+const PUMP_GRACE_PERIOD: Duration = Duration::from_secs(5);
 
+// How long `chef-client` has to exit after `SIGTERM` before it is killed.
+pub const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Environment variable telling chef-client, and so its handlers, which run
+// it is part of.
+pub const RUN_ID_ENV: &str = "CHEFCTL_RUN_ID";
+
+// Identifies a single run, e.g. `20190115T103000-5f2a9c1e`.
+pub fn new_run_id() -> String {
     format!(
-        "{}{:02}{:02}.{:02}{:02}.{}",
-        now.year(),
-        now.month(),
-        now.day(),
-        now.hour(),
-        now.minute(),
-        now.timestamp(),
+        "{}-{:08x}",
+        Local::now().format("%Y%m%dT%H%M%S"),
+        thread_rng().gen::<u32>()
     )
 }
 
-fn chef_run_log_path() -> String {
-    format!("chef.{}.out", timestamp())
-}
+// Where the log of the run `run_id` goes, see `output` in the config. A
+// template that cannot be rendered was already refused when the config was
+// loaded.
+pub fn output_path(run_id: &str) -> String {
+    let output = &CONFIG.read().unwrap().output;
+    let path = output
+        .log_path(run_id)
+        .unwrap_or_else(|_| Path::new(&output.dir).join(format!("chef.{}.out", run_id)));
 
-#[cfg(target_os = "windows")]
-pub fn output_path() -> String {
-    let file_name = chef_run_log_path();
-    let p: PathBuf = ["C:\\", "chef", "outputs", file_name.as_str()]
-        .iter()
-        .collect();
-
-    match p.to_str() {
-        Some(p) => p.to_owned(),
-        None => panic!("wtf"),
-    }
+    path.display().to_string()
 }
 
+// Logs may contain secrets, so they are only readable by root and its group.
 #[cfg(not(target_os = "windows"))]
-pub fn output_path() -> String {
-    let file_name = chef_run_log_path();
-    // for prod:
-    // let p: PathBuf = ["/", "var", "chef", "outputs", file_name.as_str()]
-    //     .iter()
-    //     .collect();
-    let p: PathBuf = ["/", "tmp", file_name.as_str()].iter().collect();
+fn create_log(path: &str) -> std::io::Result<File> {
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o750)
+            .create(dir)?;
+    }
 
-    match p.to_str() {
-        Some(p) => p.to_owned(),
-        None => panic!("wtf"),
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o640)
+        .open(path)
+}
+
+#[cfg(target_os = "windows")]
+fn create_log(path: &str) -> std::io::Result<File> {
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
     }
+
+    File::create(path)
 }
 
 lazy_static! {
-    // Subsequent calls to `output_path()` will create new timestamps, so we need to
-    // create a lazily initialized filename that is consistent across the entire run
-    // of the application.
-    pub static ref LOG_FILE_PATH: String = { output_path() };
+    // Every line `chef-client` prints during the run in progress, as it is
+    // written to the log file.
+    pub static ref OUTPUT: Broadcast = Broadcast::new();
 }
 
 // A simple splay from the thread local random number generator.
 // Since we're barely using the `rand` crate we can use other random number
 // generators if we need to that have different types of distributions.
 pub fn splay(max: u32) -> Duration {
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+
     Duration::from_secs(thread_rng().gen_range(0, max).into())
 }
 
-fn pump<'a>(mut opts: &'a mut OpenOptions, reader: &'a mut BufRead, writer: &'a mut Write) {
-    let mut log_file = open_log(LOG_FILE_PATH.to_string(), &mut opts);
-    let mut buf = String::with_capacity(BUFFER_CAPACITY);
+// Whether more output can be read without waiting for it. Output is only
+// flushed to the log before `pump` waits on a pipe that went quiet.
+pub trait Pending {
+    fn pending(&self) -> bool;
+}
+
+#[cfg(not(target_os = "windows"))]
+fn readable<F: std::os::unix::io::AsRawFd>(pipe: &F) -> bool {
+    let mut fd = libc::pollfd {
+        fd: pipe.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    unsafe { libc::poll(&mut fd, 1, 0) > 0 }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Pending for std::process::ChildStdout {
+    fn pending(&self) -> bool {
+        readable(self)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Pending for std::process::ChildStderr {
+    fn pending(&self) -> bool {
+        readable(self)
+    }
+}
+
+// Every read may block, so everything is flushed after each one.
+#[cfg(target_os = "windows")]
+impl Pending for std::process::ChildStdout {
+    fn pending(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Pending for std::process::ChildStderr {
+    fn pending(&self) -> bool {
+        false
+    }
+}
+
+// Copies what `chef-client` prints from `reader` to `writer` and, through
+// `OUTPUT`, to `log` until it closes its end of the pipe. Output is handled as
+// bytes, whatever the package managers chef-client runs print. The pipe is
+// drained even when the log or `writer` fail, `chef-client` would block on it
+// otherwise. Returns the number of bytes read.
+pub fn pump<R, L, W>(mut reader: R, log: L, mut writer: W) -> std::io::Result<u64>
+where
+    R: Read + Pending,
+    L: Write,
+    W: Write,
+{
+    let mut log = BufWriter::with_capacity(BUFFER_CAPACITY, log);
+    let mut buf = vec![0; BUFFER_CAPACITY];
+    let (mut filled, mut total) = (0, 0);
+    let (mut logging, mut echoing) = (true, true);
 
     loop {
-        match reader.read_line(&mut buf) {
-            Ok(_) => {
-                let b = buf.to_owned();
-                buf.clear();
-                match log_file.write(b.as_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => panic!("could not write to chef.cur.out: {}", e),
-                };
-                match writer.write(b.as_bytes()) {
-                    Ok(_) => {}
-                    Err(e) => panic!("could not write to stdout: {}", e),
-                };
-            }
-            Err(e) => eprintln!("{}", e),
+        let n = match reader.read(&mut buf[filled..]) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let quiet = n == 0 || (filled + n < buf.len() && !reader.pending());
+        filled += n;
+        total += n as u64;
+
+        // Whole lines are passed on so stdout and stderr are not mixed up in
+        // the middle of a line, unless a line does not fit into the buffer or
+        // nothing else is coming for now, e.g. after a prompt.
+        let end = if quiet || filled == buf.len() {
+            filled
+        } else {
+            buf[..filled]
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1)
         };
+        if end > 0 {
+            let chunk = &buf[..end];
+            // Subscribers keep getting the output when the log fails.
+            let logged = OUTPUT.publish(Bytes::from(chunk), |b| {
+                if logging {
+                    log.write_all(b)
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = logged {
+                eprintln!("could not write to the log, not logging anymore: {}", e);
+                logging = false;
+            }
+            if echoing {
+                if let Err(e) = writer.write_all(chunk) {
+                    echo_failed(&e);
+                    echoing = false;
+                }
+            }
+
+            buf[..filled].rotate_left(end);
+            filled -= end;
+        }
+
+        // Readers of the log and of our output see everything read so far
+        // before this waits for more.
+        if quiet {
+            if logging {
+                if let Err(e) = log.flush() {
+                    eprintln!("could not write to the log, not logging anymore: {}", e);
+                    logging = false;
+                }
+            }
+            if echoing {
+                if let Err(e) = writer.flush() {
+                    echo_failed(&e);
+                    echoing = false;
+                }
+            }
+        }
+
+        // The child closed its end of the pipe, nothing more will arrive.
+        if n == 0 {
+            return Ok(total);
+        }
     }
 }
 
-fn open_log(path: String, opts: &mut OpenOptions) -> File {
-    match opts.open(&path) {
-        Ok(h) => h,
-        Err(e) => panic!("error opening \"{}\": {}", path, e),
+fn echo_failed(e: &std::io::Error) {
+    // Whoever read our output went away, e.g. `chefctl | head`.
+    if e.kind() != ErrorKind::BrokenPipe {
+        eprintln!("could not echo chef-client output: {}", e);
     }
 }
 
@@ -141,42 +277,279 @@ where
     }
 }
 
+#[derive(Debug, Clone)]
+// Everything needed to perform a single run of `chef-client`.
+pub struct RunOptions {
+    // The rendered `chef-client` command line.
+    pub args: String,
+    // Maximum number of seconds to splay for before `chef-client` is launched.
+    pub splay: u32,
+    // Handed out before the run was started, e.g. by `POST /run`. `PreRun`
+    // generates one otherwise.
+    pub run_id: Option<String>,
+    pub trigger: Trigger,
+}
+
+impl RunOptions {
+    pub fn new(args: String, splay: u32) -> Self {
+        Self {
+            args,
+            splay,
+            run_id: None,
+            trigger: Trigger::Cli,
+        }
+    }
+}
+
 #[derive(Debug)]
 // Represents a handle to the chef process to-be-launched on the client.
 pub struct ChefProcess {
     inner: RefCell<Box<Command>>,
     child: Option<Result<RefCell<Child>, ExitStatus>>,
+    // Every run logs to its own file, see `output_path()`.
+    log_path: String,
 }
 
 impl ChefProcess {
     // Creates the local process but does not execute it yet. The initial
     // bookkeeping is to setup the piped `stderr` and `stdout` so output can be
     // logged to both the console as well as a log file.
-    pub fn new(cmd: String) -> Self {
+    pub fn new(cmd: String, run_id: &str, log_path: String) -> std::io::Result<Self> {
         let v: Vec<_> = cmd.split(' ').collect();
         let absolute_path: String = String::from(v[0]);
         let args = &v[1..];
         let mut cmd_line = Command::new(absolute_path);
 
         cmd_line.args(args);
+        cmd_line.env(RUN_ID_ENV, run_id);
         cmd_line.stdout(Stdio::piped());
         cmd_line.stderr(Stdio::piped());
 
         // Create the log file ahead of time so that we can open it in
         // append mode later.
-        match File::create(PathBuf::from(&*LOG_FILE_PATH)) {
-            Ok(_) => {}
-            Err(e) => panic!("could not create \"{}\": {}", &*LOG_FILE_PATH, e),
-        }
+        create_log(&log_path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("could not create \"{}\": {}", log_path, e),
+            )
+        })?;
 
         let inner = RefCell::new(Box::new(cmd_line));
 
         println!("created process: {}", cmd);
 
-        Self { inner, child: None }
+        Ok(Self {
+            inner,
+            child: None,
+            log_path,
+        })
+    }
+}
+
+// Set while a run is going through the state machine.
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+// Set by `cancel()`, checked while splaying and while `chef-client` runs.
+static CANCEL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Asks the run in progress to stop. A splaying run never launches
+// `chef-client`, a converging one is sent `SIGTERM` and, if it has not exited
+// after `KILL_GRACE_PERIOD`, killed. Returns `false` if there was nothing to
+// cancel.
+pub fn cancel() -> bool {
+    if !IN_PROGRESS.load(Ordering::SeqCst) {
+        return false;
+    }
+    CANCEL_REQUESTED.store(true, Ordering::SeqCst);
+
+    true
+}
+
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+fn cancel_requested() -> bool {
+    CANCEL_REQUESTED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    Cancelled,
+}
+
+impl Outcome {
+    // How outcomes are named everywhere, the same as in JSON.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+// What started a run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    // A slot of the daemon's schedule.
+    Schedule,
+    // `POST /run`.
+    Api,
+    // A one-shot run from the command line.
+    Cli,
+}
+
+impl Trigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Schedule => "schedule",
+            Trigger::Api => "api",
+            Trigger::Cli => "cli",
+        }
+    }
+}
+
+// How a run through the state machine ended.
+#[derive(Debug)]
+pub enum Finished {
+    PostRun(StateMachine<PostRun>),
+    Cancelled(StateMachine<Cancelled>),
+    // chef-client could not be launched.
+    Failed(StateMachine<Failed>),
+}
+
+impl Finished {
+    // `None` when the run was cancelled before `chef-client` was launched.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match self {
+            Finished::PostRun(s) => Some(s.state.exit_status),
+            Finished::Cancelled(s) => s.state.exit_status,
+            Finished::Failed(_) => None,
+        }
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        match self {
+            Finished::PostRun(s) if s.state.exit_status.success() => Outcome::Success,
+            Finished::PostRun(_) | Finished::Failed(_) => Outcome::Failure,
+            Finished::Cancelled(_) => Outcome::Cancelled,
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal()
+}
+
+#[cfg(target_os = "windows")]
+fn signal(_: ExitStatus) -> Option<i32> {
+    None
+}
+
+// Completes `record` with what the log of the run tells and appends it to the
+// history. A history that cannot be written must not get in the way of runs.
+fn record_history(mut record: RunRecord) {
+    if let Ok(f) = File::open(&record.log_path) {
+        if let Some((updated, total)) = resource_counts(BufReader::new(f)) {
+            record.resources_updated = Some(updated);
+            record.resources_total = Some(total);
+        }
+    }
+
+    let history = History::from_config();
+    if let Err(e) = history.append(&record) {
+        eprintln!(
+            "could not record run {} in the history: {}",
+            record.summary.run_id, e
+        );
     }
 }
 
+// Drives a single run through every state of the `StateMachine`.
+pub fn converge(opts: RunOptions) -> Finished {
+    CANCEL_REQUESTED.store(false, Ordering::SeqCst);
+    IN_PROGRESS.store(true, Ordering::SeqCst);
+    OUTPUT.open();
+    let started_at = Local::now();
+    let (trigger, args) = (opts.trigger, opts.args.clone());
+    let run_id = opts.run_id.clone().unwrap_or_else(new_run_id);
+
+    // How long `chef-client` ran, splay and pre-run commands excluded.
+    let mut ran_for = chrono::Duration::zero();
+    let opts = RunOptions {
+        run_id: Some(run_id.clone()),
+        ..opts
+    };
+    let finished = match StateMachine::<PreRun>::new(opts) {
+        Err(failed) => Finished::Failed(failed),
+        Ok(pre_run) => {
+            let waiting = StateMachine::<Waiting>::from(pre_run);
+            if waiting.countdown() {
+                let running_at = Local::now();
+                match waiting.launch() {
+                    Err(failed) => Finished::Failed(failed),
+                    Ok(running) => {
+                        let post_run = StateMachine::<PostRun>::from(running);
+                        ran_for = Local::now() - running_at;
+
+                        if post_run.state.cancelled {
+                            Finished::Cancelled(StateMachine::<Cancelled>::from(post_run))
+                        } else {
+                            Finished::PostRun(post_run)
+                        }
+                    }
+                }
+            } else {
+                Finished::Cancelled(StateMachine::<Cancelled>::from(waiting))
+            }
+        }
+    };
+
+    OUTPUT.close();
+    let finished_at = Local::now();
+    let summary = RunSummary {
+        run_id,
+        outcome: finished.outcome(),
+        exit_code: finished.exit_status().and_then(|s| s.code()),
+        started_at: started_at.timestamp(),
+        finished_at: finished_at.timestamp(),
+        duration: ran_for.num_milliseconds() as f64 / 1000.0,
+    };
+    APP_STATE.record_run(summary.clone());
+    crate::metrics::run_finished(&summary);
+    record_history(RunRecord {
+        trigger,
+        summary,
+        splay: APP_STATE.splay(),
+        args,
+        signal: finished.exit_status().and_then(signal),
+        log_path: APP_STATE.log_path(),
+        resources_updated: None,
+        resources_total: None,
+    });
+    crate::retention::prune_logs();
+    crate::compress::compress_in_background();
+    emit(
+        "run_finished",
+        json!({
+            "outcome": finished.outcome(),
+            "exit_code": finished.exit_status().and_then(|s| s.code()),
+        }),
+    );
+    APP_STATE.update_run_id(None);
+    IN_PROGRESS.store(false, Ordering::SeqCst);
+    CANCEL_REQUESTED.store(false, Ordering::SeqCst);
+
+    finished
+}
+
 // Represents the state of the chef process:
 //      PreRun  - Initial State, only creates an empty `Command`.
 //      Waiting - Pausing execution for the `splay` value returned between a user
@@ -184,15 +557,19 @@ impl ChefProcess {
 //      Running - The `chef-client` process is executed. Logs are piped to disk.
 //      PostRun - `chef-client` has finished execution and will bubble up the
 //                exit code.
+//    Cancelled - The run was stopped by `cancel()` while waiting or running.
+//       Failed - `chef-client` could not be launched, e.g. because its log
+//                could not be created.
 #[derive(Debug)]
 pub struct StateMachine<S> {
     state: S,
 }
 
 impl StateMachine<PreRun> {
-    pub fn new(val: String) -> Self {
-        Self {
-            state: PreRun::new(val),
+    pub fn new(opts: RunOptions) -> Result<Self, StateMachine<Failed>> {
+        match PreRun::new(opts) {
+            Ok(state) => Ok(Self { state }),
+            Err(e) => Err(StateMachine::<Failed>::new(e)),
         }
     }
 }
@@ -200,35 +577,49 @@ impl StateMachine<PreRun> {
 #[derive(Debug)]
 pub struct PreRun {
     process: ChefProcess,
+    splay: u32,
+    run_id: String,
 }
 
 impl PreRun {
-    fn new(val: String) -> Self {
-        let process = ChefProcess::new(val);
+    fn new(opts: RunOptions) -> std::io::Result<Self> {
+        let run_id = opts.run_id.unwrap_or_else(new_run_id);
+        APP_STATE.update_run_id(Some(run_id.clone()));
+        let log_path = output_path(&run_id);
+        APP_STATE.update_log_path(log_path.clone());
+        // Anyone told about the run by its state can open its log.
+        let process = ChefProcess::new(opts.args, &run_id, log_path)?;
         APP_STATE.update_process_state("pre-run".into());
 
-        Self { process }
+        Ok(Self {
+            process,
+            splay: opts.splay,
+            run_id,
+        })
     }
 }
 
 impl From<StateMachine<PreRun>> for StateMachine<Waiting> {
     fn from(val: StateMachine<PreRun>) -> StateMachine<Waiting> {
-        let chef_cur_out = &String::from(CHEF_RUN_CURRENT_PATH);
-        let chef_prev_out = &String::from(CHEF_RUN_LAST_PATH);
-        let prev_path = match std::fs::read_link(CHEF_RUN_CURRENT_PATH) {
-            Ok(s) => Some(s),
-            Err(_) => None,
-        };
+        let (chef_cur_out, chef_prev_out) = {
+            let output = &CONFIG.read().unwrap().output;
 
-        if let Some(p) = prev_path {
-            if p.to_str().unwrap() != chef_prev_out {
-                let update_symlink = &String::from(p.to_str().unwrap());
+            (output.current_path(), output.last_path())
+        };
 
-                let _ = create_symlink(chef_prev_out, update_symlink);
+        // A run without its symlinks still goes ahead.
+        if let Ok(prev_path) = std::fs::read_link(&chef_cur_out) {
+            if prev_path != chef_prev_out {
+                if let Err(e) = create_symlink(&chef_prev_out, &prev_path) {
+                    eprintln!("{}", e);
+                }
             }
         }
-        let _ = create_symlink(chef_cur_out, &LOG_FILE_PATH);
-        let duration = splay(10);
+        if let Err(e) = create_symlink(&chef_cur_out, &val.state.process.log_path) {
+            eprintln!("{}", e);
+        }
+        let duration = splay(val.state.splay);
+        APP_STATE.update_splay(duration.as_secs());
 
         APP_STATE.update_process_state("waiting".into());
         StateMachine {
@@ -251,112 +642,205 @@ impl Waiting {
         Self { process, splay }
     }
 
-    pub fn spawn(&mut self) -> Child {
-        self.process.inner.borrow_mut().spawn().unwrap()
+    pub fn spawn(&mut self) -> std::io::Result<Child> {
+        self.process.inner.borrow_mut().spawn()
     }
 }
 
-impl From<StateMachine<Waiting>> for StateMachine<Running> {
-    fn from(mut val: StateMachine<Waiting>) -> StateMachine<Running> {
+impl StateMachine<Waiting> {
+    // Counts down the splay, returns `false` if the run was cancelled meanwhile.
+    pub fn countdown(&self) -> bool {
         let one_sec = Duration::from_secs(1);
         let (tx, rx) = std::sync::mpsc::channel::<u64>();
         let _ = std::thread::Builder::new()
             .name("ticker-tx".into())
-            .spawn(move || loop {
-                let _ = tx.send(1);
-                sleep(one_sec);
+            .spawn(move || {
+                while tx.send(1).is_ok() {
+                    sleep(one_sec);
+                }
             });
 
-        let mut countdown = val.state.splay.as_secs();
+        let mut countdown = self.state.splay.as_secs();
+        APP_STATE.update_splay_countdown(countdown);
         while let Ok(_) = rx.recv() {
+            if cancel_requested() {
+                return false;
+            }
             if countdown > 0 {
                 countdown -= 1;
                 APP_STATE.update_splay_countdown(countdown);
             } else {
                 break;
             }
-            sleep(one_sec);
         }
 
+        !cancel_requested()
+    }
+}
+
+impl StateMachine<Waiting> {
+    // Starts `chef-client`, or fails the run if it cannot be spawned.
+    pub fn launch(mut self) -> Result<StateMachine<Running>, StateMachine<Failed>> {
         APP_STATE.update_process_state("running".into());
-        StateMachine {
+        let child = match self.state.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                let e =
+                    std::io::Error::new(e.kind(), format!("could not start chef-client: {}", e));
+                return Err(StateMachine::<Failed>::new(e));
+            }
+        };
+
+        Ok(StateMachine {
             state: Running {
-                child: val.state.spawn(),
+                child,
+                log_path: self.state.process.log_path,
+                pumps: Vec::new(),
             },
-        }
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct Running {
     child: Child,
+    log_path: String,
+    // Every pump reports here once the output it copies ends.
+    pumps: Vec<Receiver<std::io::Result<u64>>>,
 }
 
 impl Running {
-    fn new(child: Child) -> Self {
-        Self { child }
+    fn new(child: Child, log_path: String) -> Self {
+        Self {
+            child,
+            log_path,
+            pumps: Vec::new(),
+        }
     }
 
-    pub fn pump_stdout(&mut self) -> std::io::Result<()> {
-        let mut opts = OpenOptions::new();
-        opts.append(true);
-        opts.create(false);
+    fn start_pump<R, W>(&mut self, name: &str, reader: Option<R>, writer: W) -> std::io::Result<()>
+    where
+        R: Read + Pending + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let reader = reader.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotConnected, format!("no handle to {}", name))
+        })?;
+        let log = OpenOptions::new().append(true).open(&self.log_path)?;
+        let (tx, rx) = channel();
+
+        std::thread::Builder::new()
+            .name(format!("pump-{}", name))
+            .spawn(move || {
+                let _ = tx.send(pump(reader, log, writer));
+            })?;
+        self.pumps.push(rx);
 
-        let stdout_handle = match self.child.stdout.take() {
-            Some(s) => s,
-            None => panic!("no handle to stdout :("),
-        };
-        let mut writer = stdout();
-        let mut reader = BufReader::new(stdout_handle);
+        Ok(())
+    }
 
-        std::thread::spawn(move || {
-            writer.lock();
-            pump(&mut opts, &mut reader, &mut writer);
-        });
+    pub fn pump_stdout(&mut self) -> std::io::Result<()> {
+        let stdout_handle = self.child.stdout.take();
 
-        Ok(())
+        self.start_pump("stdout", stdout_handle, stdout())
     }
 
     pub fn pump_stderr(&mut self) -> std::io::Result<()> {
-        let mut opts = OpenOptions::new();
-        opts.append(true);
-        opts.create(false);
+        let stderr_handle = self.child.stderr.take();
 
-        let stderr_handle = match self.child.stderr.take() {
-            Some(s) => s,
-            None => panic!("no handle to stderr :("),
-        };
-        let mut writer = stderr();
-        let mut reader = BufReader::new(stderr_handle);
+        self.start_pump("stderr", stderr_handle, stderr())
+    }
 
-        std::thread::spawn(move || {
-            writer.lock();
-            pump(&mut opts, &mut reader, &mut writer);
-        });
+    // Waits for the pumps to copy what is left in the pipes once
+    // `chef-client` exited, so the log is complete.
+    fn finish_pumps(&mut self) {
+        let deadline = Instant::now() + PUMP_GRACE_PERIOD;
 
-        Ok(())
+        for rx in self.pumps.drain(..) {
+            let now = Instant::now();
+            let left = if now < deadline {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            match rx.recv_timeout(left) {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("could not read chef-client output: {}", e),
+                Err(RecvTimeoutError::Timeout) => {
+                    eprintln!(
+                        "chef-client output is still open after it exited, not waiting for it"
+                    );
+                }
+                // The pump panicked, which it told about already.
+                Err(RecvTimeoutError::Disconnected) => {}
+            }
+        }
     }
 
     pub fn run(&mut self) -> std::io::Result<Option<ExitStatus>> {
         self.child.try_wait()
     }
+
+    // Gives `chef-client` the chance to clean up after itself.
+    #[cfg(not(target_os = "windows"))]
+    pub fn terminate(&mut self) -> std::io::Result<()> {
+        match unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    // There is no graceful equivalent to `SIGTERM` for a console process.
+    #[cfg(target_os = "windows")]
+    pub fn terminate(&mut self) -> std::io::Result<()> {
+        self.child.kill()
+    }
 }
 
 impl From<StateMachine<Running>> for StateMachine<PostRun> {
     fn from(mut val: StateMachine<Running>) -> StateMachine<PostRun> {
-        let _ = val.state.pump_stdout();
-        let _ = val.state.pump_stderr();
+        for started in &[val.state.pump_stdout(), val.state.pump_stderr()] {
+            if let Err(e) = started {
+                eprintln!("chef-client output is not logged: {}", e);
+            }
+        }
+        let mut terminated_at: Option<Instant> = None;
+        let mut killed = false;
 
         loop {
             if let Ok(s) = val.state.run() {
                 if let Some(exit_status) = s {
+                    val.state.finish_pumps();
                     APP_STATE.update_process_state("post-run".into());
 
                     return StateMachine {
-                        state: PostRun { exit_status },
+                        state: PostRun {
+                            exit_status,
+                            cancelled: terminated_at.is_some(),
+                        },
                     };
                 }
             }
+
+            if cancel_requested() {
+                match terminated_at {
+                    None => {
+                        println!("cancelling chef-client");
+                        if let Err(e) = val.state.terminate() {
+                            eprintln!("could not terminate chef-client: {}", e);
+                        }
+                        terminated_at = Some(Instant::now());
+                    }
+                    Some(t) if !killed && t.elapsed() >= KILL_GRACE_PERIOD => {
+                        println!("chef-client did not exit, killing it");
+                        if let Err(e) = val.state.child.kill() {
+                            eprintln!("could not kill chef-client: {}", e);
+                        }
+                        killed = true;
+                    }
+                    _ => {}
+                }
+            }
             sleep(Duration::from_millis(500));
         }
     }
@@ -365,11 +849,72 @@ impl From<StateMachine<Running>> for StateMachine<PostRun> {
 #[derive(Debug)]
 pub struct PostRun {
     exit_status: ExitStatus,
+    // `chef-client` exited because the run was cancelled.
+    cancelled: bool,
 }
 
 impl PostRun {
     fn new(exit_status: ExitStatus) -> Self {
-        Self { exit_status }
+        Self {
+            exit_status,
+            cancelled: false,
+        }
+    }
+}
+
+impl StateMachine<PostRun> {
+    pub fn exit_status(&self) -> ExitStatus {
+        self.state.exit_status
+    }
+}
+
+impl From<StateMachine<Waiting>> for StateMachine<Cancelled> {
+    fn from(_: StateMachine<Waiting>) -> StateMachine<Cancelled> {
+        APP_STATE.update_process_state("cancelled".into());
+        APP_STATE.update_splay_countdown(0);
+
+        StateMachine {
+            state: Cancelled { exit_status: None },
+        }
+    }
+}
+
+impl From<StateMachine<PostRun>> for StateMachine<Cancelled> {
+    fn from(val: StateMachine<PostRun>) -> StateMachine<Cancelled> {
+        APP_STATE.update_process_state("cancelled".into());
+
+        StateMachine {
+            state: Cancelled {
+                exit_status: Some(val.state.exit_status),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cancelled {
+    exit_status: Option<ExitStatus>,
+}
+
+#[derive(Debug)]
+pub struct Failed {
+    error: String,
+}
+
+impl StateMachine<Failed> {
+    fn new(e: std::io::Error) -> Self {
+        APP_STATE.update_process_state("post-run".into());
+        APP_STATE.update_splay_countdown(0);
+
+        StateMachine {
+            state: Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+
+    pub fn error(&self) -> &str {
+        &self.state.error
     }
 }
 
@@ -387,4 +932,49 @@ mod test {
 
         assert_eq!(s, expected);
     }
+
+    #[test]
+    fn pumps_bytes_until_the_pipe_closes() {
+        use super::{pump, Pending};
+        use std::io::{Cursor, Read, Write};
+
+        // A pipe handing out a few bytes at a time.
+        struct Pipe(Cursor<Vec<u8>>);
+        impl Read for Pipe {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(7);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl Pending for Pipe {
+            fn pending(&self) -> bool {
+                self.0.position() < 30
+            }
+        }
+
+        // Remembers where every write ended.
+        #[derive(Default)]
+        struct Echo(Vec<u8>, Vec<usize>);
+        impl Write for Echo {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.extend_from_slice(buf);
+                self.1.push(self.0.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = b"Installing package\n\xff\xfe from a package manager\nno newline".to_vec();
+        let (mut log, mut echo) = (Vec::new(), Echo::default());
+
+        let copied = pump(Pipe(Cursor::new(output.clone())), &mut log, &mut echo).unwrap();
+
+        assert_eq!(copied, output.len() as u64);
+        assert_eq!(log, output);
+        assert_eq!(echo.0, output);
+        // The partial line read once the pipe went quiet was not held back.
+        assert!(echo.1.contains(&35));
+    }
 }
@@ -0,0 +1,309 @@
+use crate::{
+    compress::compressed_path,
+    config::{OutputConfig, RetentionConfig, CONFIG},
+    history::History,
+    process::Outcome,
+};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// A run log found in the output directory.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    // Seconds since the epoch.
+    pub modified: i64,
+    pub len: u64,
+}
+
+fn epoch_seconds(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Picks the logs to delete. `logs` has to be sorted newest first, `protected`
+// logs are never picked but count towards every limit.
+pub fn select(
+    logs: &[LogFile],
+    protected: &HashSet<PathBuf>,
+    policy: &RetentionConfig,
+    now: i64,
+) -> Vec<PathBuf> {
+    let mut total = 0;
+    let mut doomed = Vec::new();
+
+    for (i, log) in logs.iter().enumerate() {
+        let mut expired = false;
+        if let Some(n) = policy.keep_last {
+            expired |= i >= n;
+        }
+        if let Some(age) = policy.max_age {
+            expired |= now - log.modified > age as i64;
+        }
+        if let Some(max) = policy.max_total_bytes {
+            expired |= total + log.len > max;
+        }
+        let keep = protected.contains(&log.path) || !expired;
+
+        if keep {
+            total += log.len;
+        } else {
+            doomed.push(log.path.clone());
+        }
+    }
+
+    doomed
+}
+
+// Every run log in the output directory, newest first.
+pub fn run_logs(output: &OutputConfig) -> std::io::Result<Vec<LogFile>> {
+    let mut logs = Vec::new();
+
+    for entry in std::fs::read_dir(&output.dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_log = match name.to_str() {
+            Some(n) => output.is_log_name(n),
+            None => false,
+        };
+        if !is_log {
+            continue;
+        }
+        // Symlinks are not logs even when their names look like one. Logs
+        // may be compressed or deleted while the directory is scanned.
+        let meta = match entry.path().symlink_metadata() {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !meta.file_type().is_file() {
+            continue;
+        }
+
+        logs.push(LogFile {
+            path: entry.path(),
+            modified: meta.modified().map(epoch_seconds).unwrap_or(0),
+            len: meta.len(),
+        });
+    }
+    logs.sort_by_key(|l| std::cmp::Reverse(l.modified));
+
+    Ok(logs)
+}
+
+// The logs the current and last symlinks point at.
+pub fn link_targets(output: &OutputConfig) -> HashSet<PathBuf> {
+    [output.current_path(), output.last_path()]
+        .iter()
+        .filter_map(|l| std::fs::read_link(l).ok())
+        .map(|t| Path::new(&output.dir).join(t))
+        .collect()
+}
+
+// The logs behind the symlinks and those of recently failed runs. Fails when
+// the failed runs cannot be told from the history.
+fn protected(
+    output: &OutputConfig,
+    history: &History,
+    now: i64,
+) -> std::io::Result<HashSet<PathBuf>> {
+    let mut protected = link_targets(output);
+
+    if let Some(window) = output.retention.keep_failures_for {
+        let records = history.records().map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("could not read the history for keep_failures_for: {}", e),
+            )
+        })?;
+        protected.extend(
+            records
+                .into_iter()
+                .filter(|r| r.summary.outcome == Outcome::Failure)
+                .filter(|r| now - r.summary.finished_at <= window as i64)
+                .flat_map(|r| {
+                    let path = PathBuf::from(r.log_path);
+                    vec![compressed_path(&path), path]
+                }),
+        );
+    }
+
+    Ok(protected)
+}
+
+// Applies `output.retention` to the output directory. Returns the logs that
+// were deleted.
+pub fn prune(output: &OutputConfig, history: &History) -> std::io::Result<Vec<PathBuf>> {
+    let now = epoch_seconds(SystemTime::now());
+    let protected = protected(output, history, now)?;
+    let logs = run_logs(output)?;
+    let doomed = select(&logs, &protected, &output.retention, now);
+
+    let mut deleted = Vec::new();
+    for path in doomed {
+        match std::fs::remove_file(&path) {
+            Ok(()) => deleted.push(path),
+            Err(e) => eprintln!("could not delete \"{}\": {}", path.display(), e),
+        }
+    }
+
+    Ok(deleted)
+}
+
+// Prunes the run logs after a run, if any retention limit is configured.
+pub fn prune_logs() {
+    let config = CONFIG.read().unwrap();
+    let retention = &config.output.retention;
+    if retention.keep_last.is_none()
+        && retention.max_age.is_none()
+        && retention.max_total_bytes.is_none()
+    {
+        return;
+    }
+
+    match prune(&config.output, &History::from_config()) {
+        Ok(ref d) if d.is_empty() => {}
+        Ok(d) => eprintln!("deleted {} old run logs", d.len()),
+        Err(e) => eprintln!("could not prune \"{}\": {}", config.output.dir, e),
+    }
+}
+
+mod test {
+    #[test]
+    fn selects_logs_beyond_the_limits() {
+        use super::{select, LogFile};
+        use crate::config::RetentionConfig;
+        use std::{collections::HashSet, path::PathBuf};
+
+        // Newest first, one per hour, 100 bytes each.
+        let logs: Vec<LogFile> = (0..5)
+            .map(|i| LogFile {
+                path: PathBuf::from(format!("/out/{}", i)),
+                modified: 100_000 - i * 3600,
+                len: 100,
+            })
+            .collect();
+        let names = |d: Vec<PathBuf>| -> Vec<String> {
+            d.iter().map(|p| p.display().to_string()).collect()
+        };
+        let mut protected = HashSet::new();
+        protected.insert(PathBuf::from("/out/4"));
+
+        let keep_last = RetentionConfig {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            names(select(&logs, &protected, &keep_last, 100_000)),
+            vec!["/out/2", "/out/3"]
+        );
+
+        let max_age = RetentionConfig {
+            max_age: Some(2 * 3600),
+            ..Default::default()
+        };
+        assert_eq!(
+            names(select(&logs, &HashSet::new(), &max_age, 100_000)),
+            vec!["/out/3", "/out/4"]
+        );
+
+        let max_total = RetentionConfig {
+            max_total_bytes: Some(250),
+            ..Default::default()
+        };
+        assert_eq!(
+            names(select(&logs, &HashSet::new(), &max_total, 100_000)),
+            vec!["/out/2", "/out/3", "/out/4"]
+        );
+    }
+
+    #[test]
+    fn prunes_only_unprotected_run_logs() {
+        use super::{epoch_seconds, prune};
+        use crate::{
+            config::OutputConfig,
+            history::{History, RunRecord},
+            process::{Outcome, Trigger},
+            state::RunSummary,
+        };
+        use std::time::SystemTime;
+
+        let dir = std::env::temp_dir().join(format!("chefctl-retention-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("chef.20190115T103000-0000000d.out")).unwrap();
+        let output = OutputConfig {
+            dir: dir.display().to_string(),
+            retention: crate::config::RetentionConfig {
+                keep_last: Some(0),
+                keep_failures_for: Some(3600),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let log = |id: &str| dir.join(format!("chef.20190115T103000-{}.out", id));
+        for name in &["notes.txt", "chef.backup.out", "chef.20190115T103000.out"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        for id in &["0000000a", "0000000b", "0000000c"] {
+            std::fs::write(log(id), "").unwrap();
+        }
+        std::fs::write(format!("{}.gz", log("0000000b").display()), "").unwrap();
+        crate::symlink::create_symlink(output.current_path(), log("0000000a")).unwrap();
+
+        // Only the recent failure is protected.
+        let history = History::new(dir.join("history.jsonl"), 10);
+        let now = epoch_seconds(SystemTime::now());
+        for &(id, finished_at) in &[("0000000b", now - 60), ("0000000c", now - 7200)] {
+            let record = RunRecord {
+                trigger: Trigger::Schedule,
+                summary: RunSummary {
+                    run_id: id.into(),
+                    outcome: Outcome::Failure,
+                    exit_code: Some(1),
+                    started_at: finished_at,
+                    finished_at,
+                    duration: 0.0,
+                },
+                splay: 0,
+                args: String::new(),
+                signal: None,
+                log_path: log(id).display().to_string(),
+                resources_updated: None,
+                resources_total: None,
+            };
+            history.append(&record).unwrap();
+        }
+
+        // Nothing is deleted without knowing which runs failed.
+        assert!(prune(&output, &History::new(&dir, 10)).is_err());
+        assert!(log("0000000c").exists());
+
+        let deleted = prune(&output, &history).unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(deleted, vec![log("0000000c")]);
+        assert_eq!(
+            left,
+            vec![
+                "chef.20190115T103000-0000000a.out",
+                "chef.20190115T103000-0000000b.out",
+                "chef.20190115T103000-0000000b.out.gz",
+                "chef.20190115T103000-0000000d.out",
+                "chef.20190115T103000.out",
+                "chef.backup.out",
+                "chef.cur.out",
+                "history.jsonl",
+                "history.jsonl.lock",
+                "notes.txt",
+            ]
+        );
+    }
+}
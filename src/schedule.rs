@@ -0,0 +1,302 @@
+use chrono::{
+    prelude::{DateTime, Datelike, Local, TimeZone, Timelike},
+    Duration as ChronoDuration, NaiveDateTime,
+};
+use std::time::Duration;
+
+// Expressions that cannot match within this many days, e.g. `0 0 30 2 *`, are
+// rejected when parsing.
+const SEARCH_LIMIT_DAYS: i64 = 366 * 5;
+
+#[derive(Debug)]
+pub struct ScheduleError(String);
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "schedule error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+// A single cron-style expression with the usual five fields:
+//
+//      minute hour day-of-month month day-of-week
+//
+// Every field accepts `*`, single values, ranges (`1-5`), steps (`*/15`,
+// `0-30/10`) and comma separated lists of those. Day of week runs from 0 to 7
+// where both 0 and 7 are Sunday. As with cron(8), when both day fields are
+// restricted a day matches if either of them does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    dom_restricted: bool,
+    dow_restricted: bool,
+}
+
+fn parse_number(s: &str, min: u32, max: u32) -> Result<u32, ScheduleError> {
+    match s.parse::<u32>() {
+        Ok(v) if v >= min && v <= max => Ok(v),
+        _ => Err(ScheduleError(format!(
+            "\"{}\" is not a number between {} and {}",
+            s, min, max
+        ))),
+    }
+}
+
+// Turns a single field into a bit set where bit `n` means `n` matches.
+fn parse_field(spec: &str, min: u32, max: u32) -> Result<u64, ScheduleError> {
+    let mut bits = 0;
+
+    for part in spec.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => {
+                let step = parse_number(&part[i + 1..], 1, max)?;
+                (&part[..i], Some(step))
+            }
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                parse_number(&range[..i], min, max)?,
+                parse_number(&range[i + 1..], min, max)?,
+            )
+        } else {
+            let v = parse_number(range, min, max)?;
+            // `5/10` is shorthand for `5-max/10`.
+            (v, if step.is_some() { max } else { v })
+        };
+
+        if start > end {
+            return Err(ScheduleError(format!("\"{}\" is an empty range", range)));
+        }
+
+        let mut v = start;
+        while v <= end {
+            bits |= 1 << v;
+            v += step.unwrap_or(1);
+        }
+    }
+
+    Ok(bits)
+}
+
+fn is_set(bits: u64, v: u32) -> bool {
+    bits & (1 << v) != 0
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<Self, ScheduleError> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError(format!(
+                "\"{}\" must have exactly five fields",
+                expr
+            )));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        if is_set(days_of_week, 7) {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        let cron = Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            dom_restricted: !fields[2].starts_with('*'),
+            dow_restricted: !fields[4].starts_with('*'),
+        };
+
+        let epoch = NaiveDateTime::from_timestamp(0, 0);
+        if cron.next_after(epoch).is_none() {
+            return Err(ScheduleError(format!("\"{}\" never matches", expr)));
+        }
+
+        Ok(cron)
+    }
+
+    fn matches_day(&self, t: &NaiveDateTime) -> bool {
+        if !is_set(self.months, t.month()) {
+            return false;
+        }
+
+        let dom = is_set(self.days_of_month, t.day());
+        let dow = is_set(self.days_of_week, t.weekday().num_days_from_sunday());
+
+        if self.dom_restricted && self.dow_restricted {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+
+    // Returns the first minute strictly after `after` that matches.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t =
+            after.date().and_hms(after.hour(), after.minute(), 0) + ChronoDuration::minutes(1);
+        let limit = t + ChronoDuration::days(SEARCH_LIMIT_DAYS);
+
+        while t < limit {
+            if !self.matches_day(&t) {
+                t = t.date().and_hms(0, 0, 0) + ChronoDuration::days(1);
+            } else if !is_set(self.hours, t.hour()) {
+                t = t.date().and_hms(t.hour(), 0, 0) + ChronoDuration::hours(1);
+            } else if !is_set(self.minutes, t.minute()) {
+                t += ChronoDuration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+
+        None
+    }
+}
+
+// When the daemon should start its runs.
+#[derive(Debug)]
+pub enum Schedule {
+    // A fixed amount of time between the start of two runs.
+    Interval(Duration),
+    // Runs start at every slot matched by any of the expressions.
+    Cron(Vec<CronExpr>),
+}
+
+impl Schedule {
+    pub fn cron<S: AsRef<str>>(exprs: &[S]) -> Result<Self, ScheduleError> {
+        let mut v = Vec::with_capacity(exprs.len());
+        for e in exprs {
+            v.push(CronExpr::parse(e.as_ref())?);
+        }
+
+        Ok(Schedule::Cron(v))
+    }
+
+    // Returns the start of the first slot strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Interval(d) => match ChronoDuration::from_std(*d) {
+                Ok(d) => Some(after + d),
+                Err(_) => None,
+            },
+            Schedule::Cron(exprs) => exprs
+                .iter()
+                .filter_map(|e| e.next_after(after.naive_local()))
+                .min()
+                .and_then(|t| first_valid(t, |t| Local.from_local_datetime(t).earliest())),
+        }
+    }
+}
+
+// Resolves `t` with `resolve`, moving on a minute at a time while it falls
+// into a daylight saving gap, so such slots run once the clocks have moved on.
+fn first_valid<T, F>(t: NaiveDateTime, resolve: F) -> Option<T>
+where
+    F: Fn(&NaiveDateTime) -> Option<T>,
+{
+    let limit = t + ChronoDuration::days(1);
+    let mut t = t;
+
+    while t < limit {
+        if let Some(resolved) = resolve(&t) {
+            return Some(resolved);
+        }
+        t += ChronoDuration::minutes(1);
+    }
+
+    None
+}
+
+mod test {
+    #[cfg(test)]
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    #[test]
+    fn parses_steps_ranges_and_lists() {
+        use super::CronExpr;
+
+        let cron = CronExpr::parse("*/30 1-3,6 * * *").unwrap();
+
+        assert_eq!(cron.minutes, (1 << 0) | (1 << 30));
+        assert_eq!(cron.hours, (1 << 1) | (1 << 2) | (1 << 3) | (1 << 6));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        use super::CronExpr;
+
+        assert!(CronExpr::parse("* * * *").is_err());
+        assert!(CronExpr::parse("60 * * * *").is_err());
+        assert!(CronExpr::parse("5-1 * * * *").is_err());
+        assert!(CronExpr::parse("*/0 * * * *").is_err());
+        assert!(CronExpr::parse("0 0 30 2 *").is_err());
+    }
+
+    #[test]
+    fn finds_next_slot() {
+        use super::CronExpr;
+
+        let cron = CronExpr::parse("*/30 * * * *").unwrap();
+
+        assert_eq!(
+            cron.next_after(at(2019, 1, 1, 10, 7)),
+            Some(at(2019, 1, 1, 10, 30))
+        );
+        assert_eq!(
+            cron.next_after(at(2019, 1, 1, 10, 30)),
+            Some(at(2019, 1, 1, 11, 0))
+        );
+        assert_eq!(
+            cron.next_after(at(2019, 12, 31, 23, 45)),
+            Some(at(2020, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        use super::CronExpr;
+
+        // 2019-01-01 is a Tuesday.
+        let cron = CronExpr::parse("0 4 15 * 7").unwrap();
+
+        assert_eq!(
+            cron.next_after(at(2019, 1, 1, 0, 0)),
+            Some(at(2019, 1, 6, 4, 0))
+        );
+        assert_eq!(
+            cron.next_after(at(2019, 1, 13, 4, 0)),
+            Some(at(2019, 1, 15, 4, 0))
+        );
+    }
+
+    #[test]
+    fn moves_slots_in_daylight_saving_gaps_forward() {
+        use super::first_valid;
+        use chrono::Timelike;
+
+        // Clocks jump from 02:00 to 03:00.
+        let resolve = |t: &chrono::NaiveDateTime| match t.hour() {
+            2 => None,
+            _ => Some(*t),
+        };
+
+        assert_eq!(
+            first_valid(at(2019, 3, 10, 2, 30), resolve),
+            Some(at(2019, 3, 10, 3, 0))
+        );
+        assert_eq!(
+            first_valid(at(2019, 3, 10, 1, 30), resolve),
+            Some(at(2019, 3, 10, 1, 30))
+        );
+    }
+}
@@ -1,4 +1,19 @@
-use std::sync::RwLock;
+use crate::{events::emit, process::Outcome};
+use std::{collections::BTreeMap, sync::RwLock};
+
+// What is known about a run once it has left the state machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub outcome: Outcome,
+    pub exit_code: Option<i32>,
+    // Seconds since the epoch.
+    pub started_at: i64,
+    pub finished_at: i64,
+    // Seconds from entering `Running` to entering `PostRun` or `Cancelled`,
+    // 0 for runs cancelled before `chef-client` was launched.
+    pub duration: f64,
+}
 
 // Struct used to represent the global state of the application. This has to
 // "implement" the Send + Sync marker traits since it will be crossing thread
@@ -12,21 +27,113 @@ use std::sync::RwLock;
 #[derive(Serialize, Deserialize)]
 pub struct State {
     process_state: RwLock<String>,
+    run_id: RwLock<Option<String>>,
     splay_countdown: RwLock<u64>,
+    log_path: RwLock<String>,
+    next_run: RwLock<Option<String>>,
+    splay: RwLock<u64>,
+    last_run: RwLock<Option<RunSummary>>,
+    consecutive_failures: RwLock<u64>,
+    runs_total: RwLock<BTreeMap<String, u64>>,
 }
 
 impl State {
+    pub fn process_state(&self) -> String {
+        self.process_state.read().unwrap().clone()
+    }
+
     pub fn update_process_state(&self, ps: String) {
         let mut val = self.process_state.write().unwrap();
 
+        if *val != ps {
+            emit("process_state", json!({ "process_state": ps }));
+        }
         *val = ps;
     }
 
+    // The run going through the state machine, if any.
+    pub fn run_id(&self) -> Option<String> {
+        self.run_id.read().unwrap().clone()
+    }
+
+    pub fn update_run_id(&self, id: Option<String>) {
+        let mut val = self.run_id.write().unwrap();
+
+        *val = id;
+    }
+
     pub fn update_splay_countdown(&self, v: u64) {
         let mut val = self.splay_countdown.write().unwrap();
 
+        if *val != v {
+            emit("splay_countdown", json!({ "splay_countdown": v }));
+        }
         *val = v;
     }
+
+    pub fn log_path(&self) -> String {
+        self.log_path.read().unwrap().clone()
+    }
+
+    pub fn update_log_path(&self, p: String) {
+        let mut val = self.log_path.write().unwrap();
+
+        *val = p;
+    }
+
+    pub fn next_run(&self) -> Option<String> {
+        self.next_run.read().unwrap().clone()
+    }
+
+    pub fn update_next_run(&self, t: Option<String>) {
+        let mut val = self.next_run.write().unwrap();
+
+        *val = t;
+    }
+
+    pub fn splay_countdown(&self) -> u64 {
+        *self.splay_countdown.read().unwrap()
+    }
+
+    pub fn splay(&self) -> u64 {
+        *self.splay.read().unwrap()
+    }
+
+    pub fn update_splay(&self, v: u64) {
+        let mut val = self.splay.write().unwrap();
+
+        *val = v;
+    }
+
+    pub fn last_run(&self) -> Option<RunSummary> {
+        self.last_run.read().unwrap().clone()
+    }
+
+    pub fn consecutive_failures(&self) -> u64 {
+        *self.consecutive_failures.read().unwrap()
+    }
+
+    pub fn runs_total(&self) -> BTreeMap<String, u64> {
+        self.runs_total.read().unwrap().clone()
+    }
+
+    // Cancelled runs neither break nor extend a streak of failures.
+    pub fn record_run(&self, run: RunSummary) {
+        let mut failures = self.consecutive_failures.write().unwrap();
+        match run.outcome {
+            Outcome::Success => *failures = 0,
+            Outcome::Failure => *failures += 1,
+            Outcome::Cancelled => {}
+        }
+
+        let mut runs_total = self.runs_total.write().unwrap();
+        *runs_total
+            .entry(run.outcome.as_str().to_string())
+            .or_insert(0) += 1;
+
+        let mut last_run = self.last_run.write().unwrap();
+        *last_run = Some(run);
+    }
 }
 
 unsafe impl Send for State {}
@@ -35,6 +142,13 @@ unsafe impl Sync for State {}
 lazy_static! {
     pub static ref APP_STATE: State = State {
         process_state: RwLock::new(String::from("init")),
+        run_id: RwLock::new(None),
         splay_countdown: RwLock::new(0 as u64),
+        log_path: RwLock::new(String::new()),
+        next_run: RwLock::new(None),
+        splay: RwLock::new(0),
+        last_run: RwLock::new(None),
+        consecutive_failures: RwLock::new(0),
+        runs_total: RwLock::new(BTreeMap::new()),
     };
 }
@@ -0,0 +1,146 @@
+use crate::{config::StatsdConfig, platform::hostname, state::RunSummary};
+use std::{collections::BTreeMap, net::UdpSocket};
+
+// Sends metrics to a local statsd or DogStatsD agent. Delivery is fire and
+// forget, an agent that is not listening must never get in the way of a run.
+#[derive(Debug)]
+pub struct Statsd {
+    config: StatsdConfig,
+    host: String,
+}
+
+impl Statsd {
+    pub fn new(config: StatsdConfig, host: String) -> Self {
+        Self { config, host }
+    }
+
+    // Formats a single metric. `tags` are only rendered for DogStatsD.
+    fn line(&self, name: &str, value: f64, kind: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = format!("{}.{}:{}|{}", self.config.prefix, name, value, kind);
+
+        if self.config.dogstatsd {
+            let mut all: Vec<String> = vec![format!("host:{}", self.host)];
+            all.extend(self.config.tags.iter().map(|(k, v)| format!("{}:{}", k, v)));
+            all.extend(tags.iter().map(|(k, v)| format!("{}:{}", k, v)));
+
+            line.push_str("|#");
+            line.push_str(&all.join(","));
+        }
+
+        line
+    }
+
+    pub fn run_lines(&self, run: &RunSummary) -> Vec<String> {
+        let outcome = run.outcome.as_str();
+        // Not tagged with the run id, each run would start new series. The
+        // textfile's `chefctl_last_run_info` carries it.
+        let tags = [("outcome", outcome)];
+        let runs = if self.config.dogstatsd {
+            "runs".to_string()
+        } else {
+            format!("runs.{}", outcome)
+        };
+        let mut lines = vec![
+            self.line(&runs, 1.0, "c", &tags),
+            self.line("run.duration", (run.duration * 1000.0).round(), "ms", &tags),
+        ];
+        if let Some(code) = run.exit_code {
+            lines.push(self.line("run.exit_code", f64::from(code), "g", &tags));
+        }
+
+        lines
+    }
+
+    pub fn health_lines(&self, passed: &BTreeMap<String, bool>) -> Vec<String> {
+        passed
+            .iter()
+            .map(|(check, ok)| {
+                let v = if *ok { 1.0 } else { 0.0 };
+                if self.config.dogstatsd {
+                    self.line("health_check", v, "g", &[("check", check)])
+                } else {
+                    let name: String = check
+                        .chars()
+                        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                        .collect();
+                    self.line(&format!("health_check.{}", name), v, "g", &[])
+                }
+            })
+            .collect()
+    }
+
+    pub fn send(&self, lines: &[String]) -> std::io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+
+        for l in lines {
+            socket.send_to(l.as_bytes(), self.config.address.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
+// Returns a sink if `metrics.statsd` is configured.
+pub fn from_config() -> Option<Statsd> {
+    let config = crate::config::CONFIG
+        .read()
+        .unwrap()
+        .metrics
+        .statsd
+        .clone()?;
+
+    Some(Statsd::new(config, hostname()))
+}
+
+mod test {
+    #[cfg(test)]
+    fn run() -> crate::state::RunSummary {
+        crate::state::RunSummary {
+            run_id: String::new(),
+            outcome: crate::process::Outcome::Failure,
+            exit_code: Some(1),
+            started_at: 0,
+            finished_at: 2,
+            duration: 1.5,
+        }
+    }
+
+    #[test]
+    fn formats_dogstatsd_tags() {
+        use super::Statsd;
+        use crate::config::StatsdConfig;
+
+        let mut config = StatsdConfig::default();
+        config.tags.insert("role".into(), "web".into());
+        let statsd = Statsd::new(config, "node1".into());
+
+        assert_eq!(
+            statsd.run_lines(&run()),
+            vec![
+                "chefctl.runs:1|c|#host:node1,role:web,outcome:failure",
+                "chefctl.run.duration:1500|ms|#host:node1,role:web,outcome:failure",
+                "chefctl.run.exit_code:1|g|#host:node1,role:web,outcome:failure",
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_plain_statsd() {
+        use super::Statsd;
+        use crate::config::StatsdConfig;
+
+        let config = StatsdConfig {
+            dogstatsd: false,
+            ..Default::default()
+        };
+        let statsd = Statsd::new(config, "node1".into());
+        let mut health = std::collections::BTreeMap::new();
+        health.insert("Chef Client Version Check".to_string(), false);
+
+        assert_eq!(statsd.run_lines(&run())[0], "chefctl.runs.failure:1|c");
+        assert_eq!(
+            statsd.health_lines(&health),
+            vec!["chefctl.health_check.Chef_Client_Version_Check:0|g"]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    api::{CheckStatus, HealthResponse, StatusResponse, API_PREFIX},
+    client::{Client, ClientError},
+    config::CONFIG,
+    health::run_checks,
+    history::{local_time, History},
+    state::RunSummary,
+};
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+// The process holding the chef-client lock file.
+#[derive(Debug, Serialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    // False for a stale lock left behind by a process that is gone.
+    pub running: bool,
+}
+
+// Reads the pid chef-client writes into its lock file.
+pub fn lock_holder<P: AsRef<Path>>(path: P) -> Option<LockHolder> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let pid = content.trim().parse().ok()?;
+
+    Some(LockHolder {
+        pid,
+        running: process_exists(pid),
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn process_exists(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// The lock file is removed when chef-client exits.
+#[cfg(target_os = "windows")]
+fn process_exists(_: u32) -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    // The API of the running daemon.
+    Api,
+    // The lock, symlink and history files, when no daemon answered.
+    Files,
+}
+
+// What `chefctl status` reports.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub source: Source,
+    pub process_state: String,
+    pub run_id: Option<String>,
+    pub splay_countdown: Option<u64>,
+    pub next_run: Option<String>,
+    pub log_path: Option<String>,
+    pub lock_holder: Option<LockHolder>,
+    pub last_run: Option<RunSummary>,
+    pub healthy: bool,
+    pub checks: BTreeMap<String, CheckStatus>,
+}
+
+impl Report {
+    pub fn from_api<P: AsRef<Path>>(client: &Client, lock_file: P) -> Result<Self, ClientError> {
+        let resp = client.get(&format!("{}/status", API_PREFIX))?;
+        if !resp.is_success() {
+            return Err(resp.into_error());
+        }
+        let status: StatusResponse = resp.json()?;
+
+        // Failing checks are reported with 503 and the same body.
+        let resp = client.get(&format!("{}/health", API_PREFIX))?;
+        if !resp.is_success() && resp.status != 503 {
+            return Err(resp.into_error());
+        }
+        let health: HealthResponse = resp.json()?;
+
+        Ok(Self {
+            source: Source::Api,
+            process_state: status.process_state,
+            run_id: status.run_id,
+            splay_countdown: Some(status.splay_countdown),
+            next_run: status.next_run,
+            log_path: Some(status.log_path),
+            lock_holder: lock_holder(lock_file),
+            last_run: status.last_run,
+            healthy: health.healthy,
+            checks: health.checks,
+        })
+    }
+
+    // Pieces together what can be known without a daemon. The health checks
+    // are run right here.
+    pub fn from_files<P: AsRef<Path>>(lock_file: P) -> Self {
+        let lock_holder = lock_holder(lock_file);
+        let process_state = match lock_holder {
+            Some(LockHolder { running: true, .. }) => "running",
+            _ => "idle",
+        };
+        let last_run = History::from_config()
+            .records()
+            .ok()
+            .and_then(|mut r| r.pop())
+            .map(|r| r.summary);
+
+        let (results, passed) = run_checks();
+        let checks: BTreeMap<String, CheckStatus> = results
+            .into_iter()
+            .map(|(name, result)| {
+                let status = CheckStatus {
+                    passed: passed.get(&name).cloned().unwrap_or(false),
+                    result,
+                };
+
+                (name, status)
+            })
+            .collect();
+
+        Self {
+            source: Source::Files,
+            process_state: process_state.into(),
+            run_id: None,
+            splay_countdown: None,
+            next_run: None,
+            log_path: std::fs::read_link(CONFIG.read().unwrap().output.current_path())
+                .ok()
+                .map(|p| p.display().to_string()),
+            lock_holder,
+            last_run,
+            healthy: !checks.is_empty() && checks.values().all(|c| c.passed),
+            checks,
+        }
+    }
+
+    // Renders the report for people to read.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut field = |name: &str, value: &str| {
+            let _ = writeln!(out, "{:<17}{}", format!("{}:", name), value);
+        };
+
+        field("process state", &self.process_state);
+        if let Some(ref run_id) = self.run_id {
+            field("run id", run_id);
+        }
+        if let Some(countdown) = self.splay_countdown {
+            field("splay countdown", &format!("{}s", countdown));
+        }
+        if let Some(ref next_run) = self.next_run {
+            field("next run", next_run);
+        }
+        if let Some(ref log_path) = self.log_path {
+            field("log", log_path);
+        }
+        let lock = match self.lock_holder {
+            Some(LockHolder { pid, running: true }) => format!("pid {}", pid),
+            Some(LockHolder {
+                pid,
+                running: false,
+            }) => format!("pid {} (stale)", pid),
+            None => "none".into(),
+        };
+        field("lock holder", &lock);
+        let last_run = match self.last_run {
+            Some(ref r) => format!(
+                "{}, exit {}, finished {} after {:.1}s",
+                r.outcome.as_str(),
+                r.exit_code
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "-".into()),
+                local_time(r.finished_at),
+                r.duration
+            ),
+            None => "none".into(),
+        };
+        field("last run", &last_run);
+        field("health", if self.healthy { "healthy" } else { "unhealthy" });
+
+        for (name, check) in &self.checks {
+            let passed = if check.passed { "passed" } else { "FAILED" };
+            let _ = writeln!(out, "  {:<6} {} ({})", passed, name, check.result);
+        }
+
+        out
+    }
+}
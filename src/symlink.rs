@@ -1,6 +1,6 @@
 extern crate chrono;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::symlink_file as std_symlink;
@@ -8,49 +8,108 @@ use std::os::windows::fs::symlink_file as std_symlink;
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::fs::symlink as std_symlink;
 
-pub fn create_symlink<T>(link: &T, target: &T) -> std::io::Result<()>
+#[derive(Debug)]
+pub struct SymlinkError(String);
+
+impl std::fmt::Display for SymlinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "symlink error: {}", self.0)
+    }
+}
+
+impl std::error::Error for SymlinkError {}
+
+impl From<std::io::Error> for SymlinkError {
+    fn from(e: std::io::Error) -> SymlinkError {
+        SymlinkError(e.to_string())
+    }
+}
+
+// Points `link` at `target`. An existing symlink is replaced by renaming a new
+// one over it, so whoever follows `link` never finds it missing.
+pub fn create_symlink<P, Q>(link: P, target: Q) -> Result<(), SymlinkError>
 where
-    T: ToString + AsRef<std::ffi::OsStr> + AsRef<std::path::Path> + std::fmt::Debug + Sized,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
 {
-    ensure_path(link);
-    ensure_path(target);
+    let (link, target) = (link.as_ref(), target.as_ref());
+    let failed = |e: std::io::Error| {
+        SymlinkError(format!(
+            "could not point {} at {}: {}",
+            link.display(),
+            target.display(),
+            e
+        ))
+    };
+
+    ensure_path(link).map_err(failed)?;
+    ensure_symlink(link)?;
     println!("create symlink {:?} -> {:?}", link, target);
-    ensure_symlink(link.to_string())?;
-    ensure_symlink(target.to_string())?;
 
-    match std_symlink(target, link) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
+    let temporary = temporary_path(link);
+    // Left behind by a chefctl that died right here.
+    let _ = std::fs::remove_file(&temporary);
+    std_symlink(target, &temporary).map_err(failed)?;
+    if let Err(e) = std::fs::rename(&temporary, link) {
+        let _ = std::fs::remove_file(&temporary);
+
+        return Err(failed(e));
     }
+
+    Ok(())
+}
+
+// The new link is created next to the one it replaces, renames do not work
+// across file systems.
+fn temporary_path(link: &Path) -> PathBuf {
+    let name = link
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    link.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
 }
 
 // Validates that the directory structure needed for the file about to be written
 // exists.
-fn ensure_path<P>(p: P)
-where
-    PathBuf: From<P>,
-{
-    let path = PathBuf::from(p);
+fn ensure_path(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
 
-    if !path.parent().unwrap().exists() {
-        match std::fs::create_dir_all(path.parent().unwrap()) {
-            Ok(_) => {}
-            Err(e) => panic!("could not create_dir_all: {}", e),
-        }
+// Only symlinks are replaced, never a file someone put in their place.
+fn ensure_symlink(path: &Path) -> Result<(), SymlinkError> {
+    match path.symlink_metadata() {
+        Ok(ref m) if !m.file_type().is_symlink() => Err(SymlinkError(format!(
+            "{} exists and is not a symlink",
+            path.display()
+        ))),
+        _ => Ok(()),
     }
 }
 
-fn ensure_symlink(p: String) -> std::io::Result<()> {
-    let path = PathBuf::from(p);
+mod test {
+    #[test]
+    fn replaces_symlinks_but_not_files() {
+        use super::create_symlink;
 
-    if path.exists() {
-        // Only remove symlinks.
-        if let Ok(m) = path.symlink_metadata() {
-            if m.file_type().is_symlink() {
-                std::fs::remove_file(&path)?
-            }
-        }
-    }
+        let dir = std::env::temp_dir().join(format!("chefctl-symlink-{}", std::process::id()));
+        let link = dir.join("out").join("chef.cur.out");
 
-    Ok(())
+        create_symlink(&link, "chef.1.out").unwrap();
+        create_symlink(&link, "chef.2.out").unwrap();
+        assert_eq!(
+            std::fs::read_link(&link).unwrap().to_str(),
+            Some("chef.2.out")
+        );
+        assert_eq!(std::fs::read_dir(dir.join("out")).unwrap().count(), 1);
+
+        let file = dir.join("out").join("chef.last.out");
+        std::fs::write(&file, "not a link").unwrap();
+        assert!(create_symlink(&file, "chef.1.out").is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "not a link");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
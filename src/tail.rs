@@ -0,0 +1,326 @@
+use crate::{
+    compress::{self, Source},
+    history::{History, RunRecord},
+};
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+// How often the log, the symlink and the history are looked at.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// How long to wait for the symlink to the current log to show up.
+const LINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How far back from the end of the log to look for the lines shown first.
+const BACKLOG_BYTES: u64 = 64 * 1024;
+
+// How much of the log is read at a time.
+const CHUNK_BYTES: usize = 64 * 1024;
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+// Colors the lines chef-client reports errors and warnings with.
+pub fn colorize(line: &str) -> String {
+    let color = if line.contains("ERROR:") || line.contains("FATAL:") {
+        RED
+    } else if line.contains("WARN:") {
+        YELLOW
+    } else {
+        return line.to_string();
+    };
+
+    format!("{}{}{}", color, line, RESET)
+}
+
+// What `chefctl tail` exits with for a finished run. Killed runs exit like a
+// shell reports them, with 128 plus the signal.
+pub fn exit_code(record: &RunRecord) -> i32 {
+    match (record.summary.exit_code, record.signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+// The log of a run, read as it is written.
+pub struct Log {
+    path: PathBuf,
+    source: Source,
+    // The end of a line that has not been completely written yet.
+    partial: Vec<u8>,
+    color: bool,
+}
+
+impl Log {
+    pub fn open<P: AsRef<Path>>(path: P, color: bool) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let source = compress::open(&path)?;
+
+        Ok(Self {
+            path,
+            source,
+            partial: Vec::new(),
+            color,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Skips ahead to the last `lines` lines.
+    pub fn skip_to_last(&mut self, lines: usize) -> std::io::Result<()> {
+        let mut start = 0;
+        let mut tail = Vec::new();
+        match self.source {
+            Source::Plain(ref mut file) => {
+                start = file.metadata()?.len().saturating_sub(BACKLOG_BYTES);
+                file.seek(SeekFrom::Start(start))?;
+                file.read_to_end(&mut tail)?;
+            }
+            // There is no seeking in a compressed log, all of it is read.
+            Source::Compressed(ref mut file) => {
+                let mut buf = vec![0; BACKLOG_BYTES as usize];
+                loop {
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    tail.extend_from_slice(&buf[..n]);
+                    if tail.len() as u64 > 2 * BACKLOG_BYTES {
+                        let excess = tail.len() - BACKLOG_BYTES as usize;
+                        tail.drain(..excess);
+                        start += excess as u64;
+                    }
+                }
+            }
+        }
+        let mut newlines = tail
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, b)| **b == b'\n')
+            .map(|(i, _)| i);
+        // The log usually ends with a newline, which does not start a line.
+        if tail.last() == Some(&b'\n') {
+            newlines.next();
+        }
+        let from = match (lines, newlines.nth(lines.saturating_sub(1))) {
+            (0, _) => tail.len(),
+            (_, Some(i)) => i + 1,
+            (_, None) if start == 0 => 0,
+            // Fewer lines than asked for fit in the backlog, the first one is
+            // most likely cut off.
+            (_, None) => tail
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(tail.len(), |i| i + 1),
+        };
+
+        // Printed by the next `pump`.
+        self.partial = tail.split_off(from);
+
+        Ok(())
+    }
+
+    // Prints every complete line written since the last call. The log is
+    // read a chunk at a time, debug logs can be huge.
+    pub fn pump<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        // Left by `skip_to_last`.
+        self.write_complete(out)?;
+
+        let mut buf = vec![0; CHUNK_BYTES];
+        loop {
+            match self.source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.partial.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            self.write_complete(out)?;
+        }
+
+        out.flush()
+    }
+
+    fn write_complete<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        let end = match self.partial.iter().rposition(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => return Ok(()),
+        };
+        let complete: Vec<u8> = self.partial.drain(..end).collect();
+        for line in String::from_utf8_lossy(&complete).lines() {
+            self.write_line(line, out)?;
+        }
+
+        Ok(())
+    }
+
+    // Prints what is left of a log that is not written to anymore.
+    pub fn finish<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        self.pump(out)?;
+        if !self.partial.is_empty() {
+            let rest = String::from_utf8_lossy(&self.partial).into_owned();
+            self.partial.clear();
+            self.write_line(&rest, out)?;
+        }
+
+        out.flush()
+    }
+
+    fn write_line<W: Write>(&self, line: &str, out: &mut W) -> std::io::Result<()> {
+        if self.color {
+            writeln!(out, "{}", colorize(line))
+        } else {
+            writeln!(out, "{}", line)
+        }
+    }
+}
+
+// The history record of the run that wrote the log at `path`, once it has
+// finished.
+pub fn finished_run(history: &History, path: &Path) -> std::io::Result<Option<RunRecord>> {
+    Ok(history
+        .records()?
+        .into_iter()
+        .rev()
+        .find(|r| Path::new(&r.log_path) == path))
+}
+
+// Follows the log behind a symlink like `tail -F`, switching logs whenever
+// the symlink is pointed at the next run.
+pub struct Tail {
+    link: PathBuf,
+    history: History,
+    color: bool,
+    lines: usize,
+}
+
+impl Tail {
+    pub fn new<P: AsRef<Path>>(link: P, history: History, color: bool, lines: usize) -> Self {
+        Self {
+            link: link.as_ref().to_path_buf(),
+            history,
+            color,
+            lines,
+        }
+    }
+
+    // Prints the last `lines` lines of the current log and everything written
+    // after them until a run that was in progress, or the next one, finishes.
+    // Returns the exit code of that run.
+    pub fn follow<W: Write>(&self, out: &mut W) -> std::io::Result<i32> {
+        let mut current = Log::open(self.wait_for_target()?, self.color)?;
+        current.skip_to_last(self.lines)?;
+        // Runs that were already over when they were opened do not end this.
+        let mut finished_when_opened = self.finished(current.path())?.is_some();
+
+        loop {
+            current.pump(out)?;
+
+            if let Some(target) = self.target() {
+                if target != current.path() {
+                    current.finish(out)?;
+                    current = Log::open(target, self.color)?;
+                    finished_when_opened = self.finished(current.path())?.is_some();
+                    writeln!(out, "==> {} <==", current.path().display())?;
+                    continue;
+                }
+            }
+
+            if !finished_when_opened {
+                if let Some(record) = self.finished(current.path())? {
+                    current.finish(out)?;
+
+                    return Ok(exit_code(&record));
+                }
+            }
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn target(&self) -> Option<PathBuf> {
+        std::fs::read_link(&self.link).ok()
+    }
+
+    // The symlink is missing until the first run started.
+    fn wait_for_target(&self) -> std::io::Result<PathBuf> {
+        let deadline = Instant::now() + LINK_TIMEOUT;
+
+        loop {
+            if let Some(t) = self.target() {
+                return Ok(t);
+            }
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "it did not point at a log within {}s, has chefctl run yet?",
+                        LINK_TIMEOUT.as_secs()
+                    ),
+                ));
+            }
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn finished(&self, path: &Path) -> std::io::Result<Option<RunRecord>> {
+        finished_run(&self.history, path)
+    }
+}
+
+mod test {
+    #[test]
+    fn colors_errors_and_warnings() {
+        use super::colorize;
+
+        assert_eq!(
+            colorize("FATAL: Chef::Exceptions::ChildConvergeError"),
+            "\x1b[31mFATAL: Chef::Exceptions::ChildConvergeError\x1b[0m"
+        );
+        assert_eq!(
+            colorize("WARN: deprecated"),
+            "\x1b[33mWARN: deprecated\x1b[0m"
+        );
+        assert_eq!(colorize("Recipe: base::default"), "Recipe: base::default");
+    }
+
+    #[test]
+    fn reads_the_last_lines_of_compressed_logs() {
+        use super::Log;
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("chefctl-tail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chef.20190115T103000-5f2a9c1e.out");
+        let mut content = "INFO: Processing package[vim]\n".repeat(10_000);
+        content.push_str("ERROR: boom\nChef Client failed");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        crate::compress::compress(&path).unwrap();
+
+        let mut all = Vec::new();
+        Log::open(&path, false).unwrap().finish(&mut all).unwrap();
+        let mut last = Vec::new();
+        let mut log = Log::open(&path, true).unwrap();
+        log.skip_to_last(2).unwrap();
+        log.finish(&mut last).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(String::from_utf8(all).unwrap(), format!("{}\n", content));
+        assert_eq!(
+            String::from_utf8(last).unwrap(),
+            "\x1b[31mERROR: boom\x1b[0m\nChef Client failed\n"
+        );
+    }
+}
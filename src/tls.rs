@@ -0,0 +1,169 @@
+use crate::config::TlsConfig;
+use futures::{Async, Future, Poll};
+use openssl::ssl::{
+    ErrorCode, HandshakeError, ShutdownResult, SslAcceptor, SslFiletype, SslMethod, SslStream,
+    SslVerifyMode,
+};
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+// Clients that have not completed the handshake by then are dropped, so that
+// idle connections cannot use up the server.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct TlsError(String);
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "tls error: {}", self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<openssl::error::ErrorStack> for TlsError {
+    fn from(e: openssl::error::ErrorStack) -> TlsError {
+        TlsError(e.to_string())
+    }
+}
+
+// Builds the server side of TLS from the certificate and key in `config`. With
+// `client_ca` set only clients presenting a certificate signed by one of the
+// CAs in that bundle can connect.
+pub fn acceptor(config: &TlsConfig) -> Result<SslAcceptor, TlsError> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(|e| TlsError(format!("could not load \"{}\": {}", config.cert, e)))?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(|e| TlsError(format!("could not load \"{}\": {}", config.key, e)))?;
+    builder.check_private_key()?;
+
+    if let Some(ref ca) = config.client_ca {
+        builder
+            .set_ca_file(ca)
+            .map_err(|e| TlsError(format!("could not load \"{}\": {}", ca, e)))?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+// TLS failures, e.g. a client without a certificate, are protocol errors.
+fn invalid<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Drives the handshake of a connection accepted on a non-blocking socket.
+pub struct Handshake<S> {
+    inner: Option<Result<SslStream<S>, HandshakeError<S>>>,
+    deadline: Delay,
+}
+
+impl<S: Read + Write> Handshake<S> {
+    pub fn new(acceptor: &SslAcceptor, stream: S) -> Self {
+        Self {
+            inner: Some(acceptor.accept(stream)),
+            deadline: Delay::new(Instant::now() + HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        // Retrying a blocked handshake from within the task makes the socket
+        // wake this task up once it is ready again.
+        let result = match self.inner.take().expect("polled a finished handshake") {
+            Err(HandshakeError::WouldBlock(mid)) => mid.handshake(),
+            r => r,
+        };
+
+        match result {
+            Ok(s) => Ok(Async::Ready(TlsStream(s))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                self.inner = Some(Err(HandshakeError::WouldBlock(mid)));
+
+                match self.deadline.poll() {
+                    Ok(Async::NotReady) => Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "tls handshake timed out",
+                    )),
+                    // The timer is gone, the server is shutting down.
+                    Err(e) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+                }
+            }
+            Err(HandshakeError::SetupFailure(e)) => Err(invalid(e)),
+            Err(HandshakeError::Failure(mid)) => Err(invalid(mid.into_error())),
+        }
+    }
+}
+
+// A TLS connection on top of a non-blocking stream, e.g. a `TcpStream`.
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: tokio_io::AsyncRead + tokio_io::AsyncWrite> tokio_io::AsyncRead for TlsStream<S> {}
+
+impl<S: tokio_io::AsyncRead + tokio_io::AsyncWrite> tokio_io::AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(ShutdownResult::Sent) | Ok(ShutdownResult::Received) => {}
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => {}
+            Err(e) => match e.into_io_error() {
+                Ok(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(e) => return Err(e),
+                Err(e) => return Err(invalid(e)),
+            },
+        }
+
+        self.0.get_mut().shutdown()
+    }
+}
+
+impl actix_web::server::IoStream for TlsStream<tokio_tcp::TcpStream> {
+    fn shutdown(&mut self, how: std::net::Shutdown) -> io::Result<()> {
+        let _ = self.0.shutdown();
+        self.0.get_mut().shutdown(how)
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.0.get_ref().peer_addr().ok()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.0.get_mut().set_nodelay(nodelay)
+    }
+
+    fn set_linger(&mut self, dur: Option<std::time::Duration>) -> io::Result<()> {
+        self.0.get_mut().set_linger(dur)
+    }
+
+    fn set_keepalive(&mut self, dur: Option<std::time::Duration>) -> io::Result<()> {
+        self.0.get_mut().set_keepalive(dur)
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    api::{StatusResponse, API_PREFIX},
+    client::Client,
+    history::{History, RunRecord},
+    status::{lock_holder, LockHolder},
+    tail::{exit_code, finished_run, POLL_INTERVAL},
+};
+use std::{
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+// How long a run may look over before its record has to be in the history.
+// The record is written right after chef-client releases the lock.
+const RECORD_GRACE: Duration = Duration::from_secs(10);
+
+// What `chefctl wait` exits with when it gives up, like `timeout(1)`.
+pub const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+// Process states of a daemon between picking up a run and finishing it.
+const ACTIVE_STATES: &[&str] = &["pre-run", "waiting", "running", "post-run"];
+
+#[derive(Debug)]
+pub enum Waited {
+    // The run finished and left this record.
+    Finished(RunRecord),
+    TimedOut,
+    // The run is over but never made it into the history, e.g. because the
+    // chefctl running it was killed.
+    Lost,
+}
+
+impl Waited {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Waited::Finished(r) => exit_code(r),
+            Waited::TimedOut => TIMED_OUT_EXIT_CODE,
+            Waited::Lost => 1,
+        }
+    }
+}
+
+// Waits for a run another chefctl is doing, be it a daemon or a one-shot run
+// from cron.
+pub struct Waiter {
+    link: PathBuf,
+    lock_file: PathBuf,
+    history: History,
+    client: Option<Client>,
+}
+
+impl Waiter {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        link: P,
+        lock_file: Q,
+        history: History,
+        client: Option<Client>,
+    ) -> Self {
+        Self {
+            link: link.as_ref().to_path_buf(),
+            lock_file: lock_file.as_ref().to_path_buf(),
+            history,
+            client,
+        }
+    }
+
+    // The status of the daemon if it is doing a run.
+    fn daemon_run(&self) -> Option<StatusResponse> {
+        let client = self.client.as_ref()?;
+        match client.get(&format!("{}/status", API_PREFIX)) {
+            Ok(resp) if resp.is_success() => resp
+                .json::<StatusResponse>()
+                .ok()
+                .filter(|s| ACTIVE_STATES.contains(&s.process_state.as_str())),
+            _ => None,
+        }
+    }
+
+    fn lock_held(&self) -> bool {
+        match lock_holder(&self.lock_file) {
+            Some(LockHolder { running, .. }) => running,
+            None => false,
+        }
+    }
+
+    // chef-client only holds the lock once it runs, a daemon also reports
+    // runs that are still waiting for their splay.
+    fn in_progress(&self) -> bool {
+        self.lock_held() || self.daemon_run().is_some()
+    }
+
+    // The log of the run in progress, if there is one. The daemon knows it
+    // before the current link is pointed at it, e.g. during pre-run.
+    pub fn current_run(&self) -> Option<PathBuf> {
+        if let Some(status) = self.daemon_run() {
+            if !status.log_path.is_empty() {
+                return Some(PathBuf::from(status.log_path));
+            }
+        }
+        if !self.lock_held() {
+            return None;
+        }
+
+        std::fs::read_link(&self.link).ok()
+    }
+
+    // Blocks until the run writing `log` has finished, calling `poll` every
+    // time it looks, e.g. to print the output written meanwhile.
+    pub fn wait<F>(
+        &self,
+        log: &Path,
+        timeout: Option<Duration>,
+        mut poll: F,
+    ) -> std::io::Result<Waited>
+    where
+        F: FnMut() -> std::io::Result<()>,
+    {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut over_since = None;
+
+        loop {
+            poll()?;
+
+            if let Some(record) = finished_run(&self.history, log)? {
+                return Ok(Waited::Finished(record));
+            }
+            if let Some(d) = deadline {
+                if Instant::now() >= d {
+                    return Ok(Waited::TimedOut);
+                }
+            }
+            if self.in_progress() {
+                over_since = None;
+            } else if over_since.get_or_insert_with(Instant::now).elapsed() >= RECORD_GRACE {
+                return Ok(Waited::Lost);
+            }
+
+            sleep(POLL_INTERVAL);
+        }
+    }
+}